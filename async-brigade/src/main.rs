@@ -1,10 +1,18 @@
 use docopt::Docopt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream as StdUnixStream;
 use std::process::Command;
-use std::time::Instant;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 use tokio::net::UnixStream;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use utils::{Stats, UsefulDuration};
+use tokio::task::{JoinHandle, LocalSet};
+use utils::{Adaptive, Allocations, Arrivals, Bandwidth, Budget, HopEvent, OpenLoop};
+use utils::{parse_rates, Record, RingRate, Samples, SharedTrace, Stats};
+use utils::{Throughput, TraceReport, TraceRing, UsefulDuration, Work};
 
 const USAGE: &str = "
 Microbenchmark of context switch overhead.
@@ -14,29 +22,184 @@ repeatedly reading a single byte from its upstream pipe and writing it to its
 downstream pipe. One 'iteration' of the benchmark drops a byte in one end, and
 measures the time required for it to come out the other end.
 
+By default, the tasks are spawned with `tokio::spawn` on Tokio's multi-thread
+runtime, so any worker thread may steal them. If `--local` is given, the tasks
+are instead spawned with `spawn_local` on a `tokio::task::LocalSet` driven by a
+current-thread runtime, so each task stays on the thread that created it. With
+`--local-sets N`, the chain is cut into N contiguous pieces, each run by its own
+LocalSet on its own thread, with the threads pinned to distinct CPUs: a
+thread-per-core layout. If there are more LocalSets than CPUs, the assignment
+wraps around.
+
+If `--adaptive` is given, the benchmark chooses the numbers of warmups and
+iterations itself, ignoring `--warmups` and `--iters`. It warms up until the
+medians of two consecutive `--window`-sized batches of iterations agree to
+within 5%, and then measures until the 95% confidence interval of the mean (or,
+with `--quantile Q`, of the Q quantile, like 0.99) is within `--target` of the
+statistic, or until the `--duration` budget (10s by default) runs out, and
+reports whether it converged.
+
+Given `--duration TIME`, like `30s` or `500ms`, the benchmark iterates for that
+long instead of performing `--iters` iterations, and reports how many it
+completed; likewise, `--warmup-duration TIME` replaces `--warmups`. In ring
+mode these set the number of samples and the warmup interval, and in open-loop
+mode they set the number of bytes injected at each rate.
+
+If `--samples-out FILE` is given, the program writes each iteration's latency
+to FILE, one per line in seconds, for the `compare` program to analyze.
+
+If `--results FILE` is given, the program appends a JSON record of its
+parameters, the machine it ran on, and its headline statistics to FILE, for
+`compare check` to examine.
+
+If `--in-flight K` is given, the benchmark measures throughput instead of
+latency: it keeps K bytes circulating through the chain at once, and reports
+how many come out the far end per second. Each iteration collects one byte and
+injects another.
+
+If `--ring` is given, the last task writes back to the first, forming a ring,
+and the driver stays out of the loop entirely. One byte (or K, with
+`--in-flight`) circulates continuously, and a separate sampling thread measures
+how many laps the first task sees per second. `--iters` and `--warmups` are
+ignored; one sample interval (or `--warmup-duration`) is discarded as warmup.
+
+If `--trace N` is given, each task records timestamps in a preallocated ring
+buffer as it receives and sends each byte, and after measuring latency the
+program reports the distribution of per-hop wake latencies over the last N
+iterations, and the hops that were slowest to wake, along with how often they
+crossed from one CPU to another. This only works in the default latency mode.
+With `--trace-out FILE`, the program also writes the last few traced
+iterations to FILE in the Chrome Trace Event format, for viewing in Perfetto or
+`chrome://tracing`: each task gets a track showing when it was blocked in
+`read`, running, and writing, and which CPU it was on.
+
+If `--rates LIST` is given, the benchmark drives the chain open-loop: for each
+rate in LIST, in bytes per second, a timer thread injects `--iters` bytes (plus
+`--warmups` more) on a schedule, without waiting for earlier bytes to come out,
+and another thread collects them. Each byte's latency is measured from the time
+it was scheduled to be sent, so that queueing delays aren't hidden when the
+injector falls behind. The `--arrivals` option chooses between evenly spaced
+(`fixed`) and exponentially distributed (`poisson`) gaps. The program reports
+latency percentiles at each offered load.
+
+If `--payload BYTES` is given, each message is BYTES long instead of a single
+byte, and the program also reports the bandwidth of each hop, to show where the
+cost of copying the message into and out of the kernel starts to outweigh the
+cost of the context switch. Messages too large for the socket buffers can
+deadlock a ring in which every task holds one.
+
+If `--idle N` is given, the program also spawns N idle tasks, each blocked
+reading its own pipe (and thus registered with the reactor's epoll set), to see
+how a large idle population affects the active chain. With `--local-sets`, the
+idle tasks are dealt out evenly among the LocalSets.
+
+To see how the difference between threads and async tasks changes once the
+tasks do something, `--work-ns NS` makes each task spin on the CPU for NS
+nanoseconds every time it passes a byte along, and `--working-set BYTES` makes
+it write a BYTES-byte heap buffer that it holds for its whole life, as its
+state would occupy.
+
+The program reports the size of each hop's future and, if built with the
+`count-allocations` cargo feature, the number and size of the heap allocations
+spawning each hop makes. With `--local-sets`, only the main thread's hops are
+counted.
+
 If `--measure COMMAND` is given, then the program runs `COMMAND` before exiting.
 This gives an opportunity to measure the program's memory use. If `COMMAND`
 contains the string `{pid}`, each occurrence is replaced with this program's
 process ID.
 
+Finally, the program tears the chain down, and reports how long that took.
+
 Usage:
-  thread-brigade [--threads N] [--iters N] [--warmups N] [--command COMMAND] [--quiet]
+  async-brigade [options]
 
 Options:
   --threads <N>     Number of async tasks (note: not OS threads). [default: 500]
   --iters <N>       Number of iterations to perform. [default: 10000]
   --warmups <N>     Number of warmup iterations to perform before benchmarking.
                     [default: 100]
-  --command <CMD>   Command to run before exiting.
+  --duration <TIME>
+                    Iterate for TIME, like `30s`, instead of `--iters` times.
+  --warmup-duration <TIME>
+                    Warm up for TIME instead of `--warmups` iterations.
+  --local           Run tasks on LocalSets instead of the work-stealing runtime.
+  --local-sets <N>  Number of LocalSets (and threads) to divide the chain among.
+                    More than 1 implies --local. [default: 1]
+  --adaptive        Choose the numbers of warmups and iterations automatically.
+  --target <REL>    Relative error at which adaptive measurement stops.
+                    [default: 0.01]
+  --quantile <Q>    Make the adaptive target apply to the Q quantile.
+  --window <N>      Number of iterations per adaptive warmup batch.
+                    [default: 100]
+  --samples-out <FILE>
+                    Write each iteration's latency to FILE.
+  --results <FILE>  Append a record of this run's statistics to FILE.
+  --strict          Refuse to run if the environment will make results
+                    noisy.
+  --in-flight <K>   Measure throughput with K bytes in flight at once.
+  --ring            Circulate bytes around a ring, and sample the lap rate.
+  --samples <N>     Number of lap rate samples to take in ring mode.
+                    [default: 20]
+  --sample-ms <MS>  Length of each lap rate sample, in milliseconds.
+                    [default: 100]
+  --trace <N>       Trace each hop of the last N iterations.
+  --trace-out <FILE>
+                    Write traced iterations to FILE as Chrome Trace JSON.
+  --trace-out-iters <K>
+                    Number of iterations to write to the trace file.
+                    [default: 3]
+  --rates <LIST>    Drive the chain open-loop at each of these comma-separated
+                    rates, in bytes per second.
+  --arrivals <A>    Spacing of open-loop arrivals: `fixed` or `poisson`.
+                    [default: poisson]
+  --payload <BYTES>
+                    Size of each message, in bytes. [default: 1]
+  --idle <N>        Number of additional idle tasks. [default: 0]
+  --work-ns <NS>    Nanoseconds of CPU work per hop. [default: 0]
+  --working-set <BYTES>
+                    Bytes of state each task writes per hop. [default: 0]
+  --command <CMD>   Command to run before tearing down.
   --quiet           Don't print time measurements.
 ";
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Args {
     flag_threads: usize,
     flag_iters: usize,
     flag_warmups: usize,
+    flag_duration: Option<UsefulDuration>,
+    flag_warmup_duration: Option<UsefulDuration>,
+    flag_local: bool,
+    flag_local_sets: usize,
+    flag_adaptive: bool,
+    flag_target: f64,
+    flag_quantile: Option<f64>,
+    flag_window: usize,
+    #[serde(skip_serializing)]
+    flag_samples_out: Option<String>,
+    #[serde(skip_serializing)]
+    flag_results: Option<String>,
+    #[serde(skip_serializing)]
+    flag_strict: bool,
+    flag_in_flight: Option<usize>,
+    flag_ring: bool,
+    flag_samples: usize,
+    flag_sample_ms: u64,
+    flag_trace: Option<usize>,
+    #[serde(skip_serializing)]
+    flag_trace_out: Option<String>,
+    #[serde(skip_serializing)]
+    flag_trace_out_iters: usize,
+    flag_rates: Option<String>,
+    flag_arrivals: Arrivals,
+    flag_payload: usize,
+    flag_idle: usize,
+    flag_work_ns: u64,
+    flag_working_set: usize,
+    #[serde(skip_serializing)]
     flag_command: Option<String>,
+    #[serde(skip_serializing)]
     flag_quiet: bool,
 }
 
@@ -50,62 +213,731 @@ fn pipe() -> Result<Pipe, std::io::Error> {
     Ok(Pipe { read, write })
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// One task's ends of the brigade, as standard sockets.
+struct StdHop {
+    read: StdUnixStream,
+    write: StdUnixStream,
+    laps: Option<Arc<AtomicUsize>>,
+    trace: Option<SharedTrace>,
+    prime: usize,
+    params: HopParams,
+}
+
+/// What each hop does with each message.
+#[derive(Clone, Copy)]
+struct HopParams {
+    payload: usize,
+    work: Work,
+}
+
+impl HopParams {
+    fn new(args: &Args) -> HopParams {
+        HopParams {
+            payload: args.flag_payload,
+            work: Work { spin_ns: args.flag_work_ns, working_set: args.flag_working_set },
+        }
+    }
+}
+
+/// The body of each task in the brigade: pass messages from upstream to
+/// downstream until either pipe is closed, as directed by `params`. If `laps`
+/// is given, count each message received in it. If `trace` is given, record
+/// each message's passage in it.
+///
+/// To start a ring circulating, the task first writes `prime` messages
+/// downstream. Priming from within the ring, rather than before spawning it,
+/// keeps large messages from filling the socket buffer with no one to drain it.
+async fn hop(mut upstream_read: UnixStream, mut downstream_write: UnixStream,
+             laps: Option<Arc<AtomicUsize>>, trace: Option<SharedTrace>,
+             prime: usize, params: HopParams)
+             -> std::io::Result<()> {
+    let HopParams { payload, work } = params;
+    let mut buf = vec![b'*'; payload];
+    for _i in 0..prime {
+        downstream_write.write_all(&buf).await?;
+    }
+
+    let mut state = vec![0_u8; work.working_set];
+    let mut event = HopEvent::default();
+    loop {
+        upstream_read.read_exact(&mut buf).await?;
+        if trace.is_some() {
+            event.received = utils::now_ns();
+            event.cpu = utils::current_cpu();
+        }
+        if let Some(laps) = &laps {
+            laps.fetch_add(1, Ordering::Relaxed);
+        }
+        if !work.is_empty() {
+            work.perform_in(&mut state);
+        }
+        if trace.is_some() {
+            event.sending = utils::now_ns();
+        }
+        downstream_write.write_all(&buf).await?;
+        if let Some(trace) = &trace {
+            event.sent = utils::now_ns();
+            trace.lock().unwrap().record(event);
+        }
+    }
+}
+
+/// The body of an idle task: wait for a byte that never arrives, until the
+/// pipe is closed.
+async fn idle(mut read: UnixStream) -> std::io::Result<()> {
+    read.read_exact(&mut [0_u8; 1]).await?;
+    Ok(())
+}
+
+/// Run the benchmark proper and report the results. If `ends` is `None`, the
+/// brigade is a ring, and `laps` is its lap counter. If we're tracing, `traces`
+/// holds the hops' traces, in order.
+async fn measure(args: &Args, ends: Option<(UnixStream, UnixStream)>, laps: Arc<AtomicUsize>,
+                 traces: &[SharedTrace])
+                 -> Result<(), Box<dyn std::error::Error>>
+{
+    match ends {
+        Some((first_write, upstream_read)) => drive(args, first_write, upstream_read, traces).await,
+        None => Ok(ring(args, laps).await?),
+    }
+}
+
+/// Run the benchmark proper, given the ends of the chain, and report the
+/// results.
+async fn drive(args: &Args, first_write: UnixStream, upstream_read: UnixStream,
+               traces: &[SharedTrace])
+               -> Result<(), Box<dyn std::error::Error>>
+{
+    if let Some(list) = &args.flag_rates {
+        open_loop(args, parse_rates(list)?, first_write, upstream_read).await?;
+    } else if let Some(in_flight) = args.flag_in_flight {
+        throughput(args, in_flight, first_write, upstream_read).await?;
+    } else {
+        let mut trace = args.flag_trace.map(TraceRing::with_capacity);
+        let mut samples = latency(args, first_write, upstream_read, trace.as_mut()).await?;
+        report(args, &samples.stats());
+        if let Some(path) = &args.flag_samples_out {
+            samples.write(std::io::BufWriter::new(std::fs::File::create(path)?))?;
+        }
+        if let Some(path) = &args.flag_results {
+            let mut record = Record::new("async-brigade", args);
+            record.latencies(&mut samples);
+            record.append_to(path)?;
+        }
+        if let Some(trace) = &trace {
+            let tasks: Vec<_> = traces.iter().map(|t| t.lock().unwrap()).collect();
+            if !args.flag_quiet {
+                eprint!("{}", TraceReport::new(trace, &tasks));
+            }
+            if let Some(path) = &args.flag_trace_out {
+                let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+                utils::write_chrome_trace(file, trace, &tasks, args.flag_trace_out_iters)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Drop bytes into `first_write` and time how long they take to come out of
+/// `upstream_read`. If `trace` is given, record each iteration in it. In
+/// adaptive mode, report whether the measurement converged.
+async fn latency(args: &Args, mut first_write: UnixStream, mut upstream_read: UnixStream,
+                 mut trace: Option<&mut TraceRing>)
+                 -> Result<Samples, std::io::Error>
+{
+    let message = vec![b'*'; args.flag_payload];
+    let mut buf = vec![0_u8; args.flag_payload];
+
+    if args.flag_adaptive {
+        let mut sampler = adaptive(args).start();
+        while sampler.wants_more() {
+            let start = Instant::now();
+            round_trip(&mut first_write, &mut upstream_read, &message, &mut buf, trace.as_deref_mut())
+                .await?;
+            sampler.push((Instant::now() - start).as_secs_f64());
+        }
+
+        let outcome = sampler.finish();
+        if !args.flag_quiet {
+            eprintln!("{}", outcome);
+        }
+        return Ok(outcome.samples);
+    }
+
+    // Warm up.
+    for _i in warmups(args).start() {
+        round_trip(&mut first_write, &mut upstream_read, &message, &mut buf, trace.as_deref_mut())
+            .await?;
+    }
+
+    let mut samples = Samples::new();
+    let mut countdown = iters(args).start();
+    for _i in &mut countdown {
+        let start = Instant::now();
+        round_trip(&mut first_write, &mut upstream_read, &message, &mut buf, trace.as_deref_mut())
+            .await?;
+        let end = Instant::now();
+
+        samples.push(UsefulDuration::from(end - start).into());
+    }
+    if !args.flag_quiet && args.flag_duration.is_some() {
+        eprintln!("{}", countdown);
+    }
+
+    Ok(samples)
+}
+
+fn adaptive(args: &Args) -> Adaptive {
+    Adaptive {
+        window: args.flag_window,
+        stability: 0.05,
+        target: args.flag_target,
+        quantile: args.flag_quantile,
+        budget: args.flag_duration.map_or(Duration::from_secs(10), Duration::from),
+    }
+}
+
+fn iters(args: &Args) -> Budget {
+    Budget::new(args.flag_iters, args.flag_duration)
+}
+
+fn warmups(args: &Args) -> Budget {
+    Budget::new(args.flag_warmups, args.flag_warmup_duration)
+}
+
+/// The number of lap rate samples to take in ring mode: `--samples`, or enough
+/// to fill `--duration`.
+fn ring_samples(args: &Args) -> usize {
+    Budget::new(args.flag_samples, args.flag_duration)
+        .iterations_at(1000.0 / args.flag_sample_ms as f64)
+}
+
+/// Send one message through the chain, recording it in `trace` if given.
+async fn round_trip(first_write: &mut UnixStream, upstream_read: &mut UnixStream,
+                    message: &[u8], buf: &mut [u8], trace: Option<&mut TraceRing>)
+                    -> Result<(), std::io::Error>
+{
+    match trace {
+        Some(trace) => {
+            let cpu = utils::current_cpu();
+            let sending = utils::now_ns();
+            first_write.write_all(message).await?;
+            let sent = utils::now_ns();
+            upstream_read.read_exact(buf).await?;
+            trace.record(HopEvent { received: utils::now_ns(), sending, sent, cpu });
+        }
+        None => {
+            first_write.write_all(message).await?;
+            upstream_read.read_exact(buf).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Keep `in_flight` bytes circulating from `first_write` to `upstream_read`,
+/// and measure how many come out per second.
+async fn throughput(args: &Args, in_flight: usize,
+                    mut first_write: UnixStream, mut upstream_read: UnixStream)
+                    -> Result<(), std::io::Error>
+{
+    let message = vec![b'*'; args.flag_payload];
+    let mut buf = vec![0_u8; args.flag_payload];
+
+    for _i in 0..in_flight {
+        first_write.write_all(&message).await?;
+    }
+
+    // Warm up.
+    for _i in warmups(args).start() {
+        upstream_read.read_exact(&mut buf).await?;
+        first_write.write_all(&message).await?;
+    }
+
+    let start = Instant::now();
+    let mut countdown = iters(args).start();
+    for _i in &mut countdown {
+        upstream_read.read_exact(&mut buf).await?;
+        first_write.write_all(&message).await?;
+    }
+    let end = Instant::now();
+
+    // Collect the bytes still in flight.
+    for _i in 0..in_flight {
+        upstream_read.read_exact(&mut buf).await?;
+    }
+
+    let throughput = Throughput {
+        messages: countdown.completed(),
+        elapsed: (end - start).as_secs_f64(),
+        tasks: args.flag_threads,
+        payload: args.flag_payload,
+    };
+    if !args.flag_quiet {
+        if args.flag_duration.is_some() {
+            eprintln!("{}", countdown);
+        }
+        eprintln!("{}", throughput);
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("async-brigade", args);
+        record.higher_is_better("messages/s", throughput.messages_per_sec());
+        record.append_to(path)?;
+    }
+
+    Ok(())
+}
+
+/// Drive the chain open-loop at each of `rates`, and report the latency
+/// distribution at each.
+///
+/// The open-loop driver blocks, so it gets threads of its own, and standard
+/// sockets to go with them.
+async fn open_loop(args: &Args, rates: Vec<f64>, first_write: UnixStream, upstream_read: UnixStream)
+                   -> Result<(), std::io::Error>
+{
+    let mut first_write = first_write.into_std()?;
+    let mut upstream_read = upstream_read.into_std()?;
+    first_write.set_nonblocking(false)?;
+    upstream_read.set_nonblocking(false)?;
+
+    let plans: Vec<_> = rates.into_iter()
+        .map(|rate| OpenLoop {
+            rate,
+            arrivals: args.flag_arrivals,
+            warmups: warmups(args).iterations_at(rate),
+            tokens: iters(args).iterations_at(rate),
+            payload: args.flag_payload,
+            seed: 1,
+        })
+        .collect();
+    let results = tokio::task::spawn_blocking(move || {
+        plans.into_iter()
+            .map(|plan| Ok((plan.rate, plan.run(&mut first_write, &mut upstream_read)?)))
+            .collect::<std::io::Result<Vec<_>>>()
+    })
+        .await
+        .expect("open-loop driver panicked")?;
+
+    let mut quantiles = Vec::with_capacity(results.len());
+    for (rate, mut results) in results {
+        if !args.flag_quiet {
+            eprintln!("{}", results.describe(rate));
+        }
+        quantiles.push((rate, results.corrected.quantile(0.5), results.corrected.quantile(0.99)));
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("async-brigade", args);
+        for (rate, p50, p99) in quantiles {
+            record.lower_is_better(&format!("p50 at {}/s", rate), p50);
+            record.lower_is_better(&format!("p99 at {}/s", rate), p99);
+        }
+        record.append_to(path)?;
+    }
+
+    Ok(())
+}
+
+/// Sample the lap rate of a ring from a separate thread, and report the
+/// results.
+async fn ring(args: &Args, laps: Arc<AtomicUsize>) -> Result<(), std::io::Error> {
+    let interval = Duration::from_millis(args.flag_sample_ms);
+    let warmup = args.flag_warmup_duration.map_or(interval, Duration::from);
+    let samples = ring_samples(args);
+    let passes = tokio::task::spawn_blocking(move || {
+        utils::sample_rate(&laps, warmup, interval, samples)
+    })
+        .await
+        .expect("sampling thread panicked");
+    let rate = RingRate {
+        passes,
+        tasks: args.flag_threads,
+        in_flight: args.flag_in_flight.unwrap_or(1),
+        payload: args.flag_payload,
+    };
+
+    if !args.flag_quiet {
+        eprintln!("{}", rate);
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("async-brigade", args);
+        record.higher_is_better("laps/s", rate.laps_per_sec());
+        record.append_to(path)?;
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
     if !args.flag_quiet {
-        eprintln!("{} tasks, {} iterations:", args.flag_threads, args.flag_iters);
+        eprintln!("environment: {}", utils::Environment::capture());
+    }
+    utils::preflight(args.flag_strict)?;
+
+    if args.flag_local_sets == 0 {
+        Err("--local-sets must be at least 1")?;
+    }
+    if args.flag_in_flight == Some(0) {
+        Err("--in-flight must be at least 1")?;
+    }
+    if args.flag_ring && args.flag_threads == 0 {
+        Err("a ring needs at least one task")?;
+    }
+    if args.flag_payload == 0 {
+        Err("--payload must be at least 1")?;
+    }
+    if args.flag_sample_ms == 0 {
+        Err("--sample-ms must be at least 1")?;
+    }
+    if let Some(list) = &args.flag_rates {
+        parse_rates(list)?;
+        if args.flag_ring || args.flag_in_flight.is_some() {
+            Err("--rates can't be combined with --ring or --in-flight")?;
+        }
+    }
+    match args.flag_trace {
+        Some(0) => Err("--trace must be at least 1")?,
+        Some(_) if args.flag_ring || args.flag_in_flight.is_some() || args.flag_rates.is_some() => {
+            Err("--trace only works when measuring latency")?
+        }
+        _ => (),
+    }
+    if args.flag_trace_out.is_some() && args.flag_trace.is_none() {
+        Err("--trace-out requires --trace")?;
+    }
+    if args.flag_samples_out.is_some()
+        && (args.flag_ring || args.flag_in_flight.is_some() || args.flag_rates.is_some())
+    {
+        Err("--samples-out only works when measuring latency")?;
+    }
+    if args.flag_adaptive {
+        if args.flag_ring || args.flag_in_flight.is_some() || args.flag_rates.is_some() {
+            Err("--adaptive only works when measuring latency")?;
+        }
+        if args.flag_window < 2 || args.flag_target <= 0.0 {
+            Err("--window must be at least 2, and --target must be positive")?;
+        }
+        if !matches!(args.flag_quantile, None | Some(0.0..=1.0)) {
+            Err("--quantile must be between 0 and 1")?;
+        }
     }
 
-    let Pipe { read: mut upstream_read, write: mut first_write} = pipe()?;
+    if !args.flag_quiet {
+        let mut layout = String::new();
+        if args.flag_idle > 0 {
+            layout += &format!(" (+{} idle)", args.flag_idle);
+        }
+        if args.flag_local || args.flag_local_sets > 1 {
+            layout += &format!(" on {} LocalSets", args.flag_local_sets);
+        }
+        if args.flag_payload != 1 {
+            layout += &format!(", {} byte messages", args.flag_payload);
+        }
+        let work = HopParams::new(&args).work;
+        if !work.is_empty() {
+            layout += &format!(", {}ns work and {} byte working set per hop",
+                               work.spin_ns, work.working_set);
+        }
+        if args.flag_ring {
+            eprintln!("{} tasks{} in a ring, {} in flight, {} samples of {}ms:",
+                      args.flag_threads, layout, args.flag_in_flight.unwrap_or(1),
+                      ring_samples(&args), args.flag_sample_ms);
+        } else if args.flag_adaptive {
+            eprintln!("{} tasks{}, adaptive iterations:", args.flag_threads, layout);
+        } else if args.flag_rates.is_some() {
+            eprintln!("{} tasks{}, {:?} arrivals, {} per rate:",
+                      args.flag_threads, layout, args.flag_arrivals, iters(&args));
+        } else {
+            let in_flight = match args.flag_in_flight {
+                Some(k) => format!(", {} in flight", k),
+                None => String::new(),
+            };
+            eprintln!("{} tasks{}{}, {}:",
+                      args.flag_threads, layout, in_flight, iters(&args));
+        }
+    }
+
+    // Every hop and idle task has a pipe to read from.
+    let fds = 2 * (args.flag_threads + args.flag_idle + 1);
+    utils::Needs { fds, ..Default::default() }.check()?;
+
+    if args.flag_local || args.flag_local_sets > 1 {
+        local_sets(&args)
+    } else {
+        tokio::runtime::Runtime::new()?.block_on(work_stealing(&args))
+    }
+}
+
+/// Run the brigade with `tokio::spawn` on the default multi-thread runtime.
+async fn work_stealing(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    // Keep a second handle on the writing end of the first pipe, for tearing
+    // the brigade down.
+    let (first_read, first_write) = StdUnixStream::pair()?;
+    let head = first_write.try_clone()?;
+    first_read.set_nonblocking(true)?;
+    first_write.set_nonblocking(true)?;
+    let mut upstream_read = UnixStream::from_std(first_read)?;
+    let first_write = UnixStream::from_std(first_write)?;
+    let mut hops = Vec::with_capacity(args.flag_threads);
     for _i in 0..args.flag_threads {
         let next_pipe = pipe()?;
-        let mut downstream_write = next_pipe.write;
-        tokio::spawn(async move {
-            let mut buf = [0_u8; 1];
+        hops.push((upstream_read, next_pipe.write));
+        upstream_read = next_pipe.read;
+    }
 
-            // Establish 'async' block's return type. Yeah.
-            if false {
-                return Ok::<(), std::io::Error>(());
-            }
+    // To close the ring, hand the first pipe's writing end to the last task,
+    // which primes it.
+    let laps = Arc::new(AtomicUsize::new(0));
+    let ends = if args.flag_ring {
+        hops.last_mut().unwrap().1 = first_write;
+        None
+    } else {
+        Some((first_write, upstream_read))
+    };
 
-            loop {
-                assert_eq!(upstream_read.read_exact(&mut buf).await?, 1);
-                downstream_write.write_all(&buf).await?;
-            }
-        });
-        upstream_read = next_pipe.read;
+    let num_hops = hops.len();
+    let mut tasks = Vec::with_capacity(num_hops + args.flag_idle);
+    let mut traces = vec![];
+    let mut future_size = 0;
+    let mut allocations = Allocations::now().map(|_| Allocations::default());
+    for (i, (read, write)) in hops.into_iter().enumerate() {
+        let laps = (args.flag_ring && i == 0).then(|| laps.clone());
+        let trace = args.flag_trace.map(TraceRing::shared);
+        traces.extend(trace.clone());
+        let prime = if args.flag_ring && i + 1 == num_hops {
+            args.flag_in_flight.unwrap_or(1)
+        } else {
+            0
+        };
+        let future = hop(read, write, laps, trace, prime, HopParams::new(args));
+        future_size = std::mem::size_of_val(&future);
+        let (task, spawned) = Allocations::during(|| tokio::spawn(future));
+        tasks.push(task);
+        allocations = allocations.zip(spawned).map(|(total, spawned)| total + spawned);
     }
+    report_spawn_cost(args, future_size, num_hops, allocations);
 
-    let mut buf = [0_u8; 1];
+    // Keep the idle pipes' writing ends open until we're done.
+    let mut idle_writes = Vec::with_capacity(args.flag_idle);
+    for _i in 0..args.flag_idle {
+        let Pipe { read, write } = pipe()?;
+        tasks.push(tokio::spawn(idle(read)));
+        idle_writes.push(write);
+    }
 
-    // Warm up.
-    for _i in 0..args.flag_warmups {
-        first_write.write_all(b"*").await?;
-        upstream_read.read_exact(&mut buf).await?;
+    measure(args, ends, laps, &traces).await?;
+    run_command(args)?;
+    tear_down(args, head, idle_writes, tasks, vec![]).await?;
+    Ok(())
+}
+
+/// Run the brigade on `args.flag_local_sets` LocalSets, each on its own thread.
+///
+/// The main thread runs the first piece of the chain, along with the driver.
+fn local_sets(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let num_sets = args.flag_local_sets;
+    let cpus = utils::available_cpus()?;
+
+    // Tokio sockets belong to the reactor of the runtime that created them, so
+    // build the chain out of standard sockets, and let each LocalSet's thread
+    // convert its own hops' ends.
+    let mut hops = Vec::with_capacity(args.flag_threads);
+    let (mut upstream_read, first_write) = StdUnixStream::pair()?;
+    let head = first_write.try_clone()?;
+    for _i in 0..args.flag_threads {
+        let (next_read, downstream_write) = StdUnixStream::pair()?;
+        hops.push(StdHop { read: upstream_read, write: downstream_write, laps: None,
+                          trace: args.flag_trace.map(TraceRing::shared), prime: 0,
+                          params: HopParams::new(args) });
+        upstream_read = next_read;
     }
 
-    let mut stats = Stats::new();
-    for _i in 0..args.flag_iters {
-        let start = Instant::now();
-        first_write.write_all(b"*").await?;
-        upstream_read.read_exact(&mut buf).await?;
-        let end = Instant::now();
+    // To close the ring, hand the first pipe's writing end to the last task,
+    // which primes it.
+    let laps = Arc::new(AtomicUsize::new(0));
+    let ends = if args.flag_ring {
+        let last = hops.last_mut().unwrap();
+        last.write = first_write;
+        last.prime = args.flag_in_flight.unwrap_or(1);
+        hops[0].laps = Some(laps.clone());
+        None
+    } else {
+        first_write.set_nonblocking(true)?;
+        upstream_read.set_nonblocking(true)?;
+        Some((first_write, upstream_read))
+    };
+    for hop in &hops {
+        hop.read.set_nonblocking(true)?;
+        hop.write.set_nonblocking(true)?;
+    }
+    let traces: Vec<_> = hops.iter().filter_map(|hop| hop.trace.clone()).collect();
 
-        stats.push(UsefulDuration::from(end - start).into());
+    // Cut the chain into contiguous pieces, one per LocalSet.
+    let mut pieces = Vec::with_capacity(num_sets);
+    let mut hops = hops.into_iter();
+    for set in 0..num_sets {
+        let len = (set + 1) * args.flag_threads / num_sets - set * args.flag_threads / num_sets;
+        pieces.push(Piece { hops: hops.by_ref().take(len).collect(), idle: vec![] });
     }
 
-    if !args.flag_quiet {
-        eprintln!("mean {} per iteration, stddev {} ({} per task per iter)",
+    // Deal out the idle tasks, keeping their pipes' writing ends open until
+    // we're done.
+    let mut idle_writes = Vec::with_capacity(args.flag_idle);
+    for i in 0..args.flag_idle {
+        let (read, write) = StdUnixStream::pair()?;
+        read.set_nonblocking(true)?;
+        pieces[i % num_sets].idle.push(read);
+        idle_writes.push(write);
+    }
+    let mut pieces = pieces.into_iter().enumerate();
+    let (_, main_piece) = pieces.next().unwrap();
+
+    // Start a thread for every piece but the first, and wait for them all to
+    // have spawned their tasks. Each thread exits once its tasks have.
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let mut threads = Vec::with_capacity(num_sets - 1);
+    for (set, piece) in pieces {
+        let cpu = cpus[set % cpus.len()];
+        let ready_tx = ready_tx.clone();
+        threads.push(std::thread::spawn(move || {
+            let result = (|| -> std::io::Result<_> {
+                utils::pin_current_thread(cpu)?;
+                tokio::runtime::Builder::new_current_thread().enable_all().build()
+            })();
+            let runtime = match result {
+                Ok(runtime) => runtime,
+                Err(e) => return ready_tx.send(Err(e)).unwrap(),
+            };
+            let local = LocalSet::new();
+            local.block_on(&runtime, async move {
+                let tasks = match spawn_piece(piece) {
+                    Ok(spawned) => spawned.tasks,
+                    Err(e) => return ready_tx.send(Err(e)).unwrap(),
+                };
+                ready_tx.send(Ok(())).unwrap();
+                for task in tasks {
+                    let _ = task.await;
+                }
+            })
+        }));
+    }
+    for _set in 1..num_sets {
+        ready_rx.recv()??;
+    }
+
+    if num_sets > 1 {
+        utils::pin_current_thread(cpus[0])?;
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let local = LocalSet::new();
+    local.block_on(&runtime, async move {
+        let num_hops = main_piece.hops.len();
+        let SpawnedPiece { future_size, allocations, tasks } = spawn_piece(main_piece)?;
+        report_spawn_cost(args, future_size, num_hops, allocations);
+        let ends = match ends {
+            Some((first_write, upstream_read)) => Some((UnixStream::from_std(first_write)?,
+                                                        UnixStream::from_std(upstream_read)?)),
+            None => None,
+        };
+        measure(args, ends, laps, &traces).await?;
+        run_command(args)?;
+        tear_down(args, head, idle_writes, tasks, threads).await?;
+        Ok(())
+    })
+}
+
+/// The tasks one LocalSet is responsible for.
+struct Piece {
+    hops: Vec<StdHop>,
+    idle: Vec<StdUnixStream>,
+}
+
+/// Spawn a task on the current `LocalSet` for each hop and idle task in
+/// `piece`. Return the size of each hop's future, and the tasks' handles.
+fn spawn_piece(piece: Piece) -> std::io::Result<SpawnedPiece> {
+    let mut future_size = 0;
+    let mut allocations = Allocations::now().map(|_| Allocations::default());
+    let mut tasks = Vec::with_capacity(piece.hops.len() + piece.idle.len());
+    for StdHop { read, write, laps, trace, prime, params } in piece.hops {
+        let future = hop(UnixStream::from_std(read)?, UnixStream::from_std(write)?,
+                         laps, trace, prime, params);
+        future_size = std::mem::size_of_val(&future);
+        let (task, spawned) = Allocations::during(|| tokio::task::spawn_local(future));
+        tasks.push(task);
+        allocations = allocations.zip(spawned).map(|(total, spawned)| total + spawned);
+    }
+    for read in piece.idle {
+        tasks.push(tokio::task::spawn_local(idle(UnixStream::from_std(read)?)));
+    }
+    Ok(SpawnedPiece { future_size, allocations, tasks })
+}
+
+/// The tasks `spawn_piece` spawned, the size of each hop's future, and the
+/// heap allocations spawning the hops made, if we counted them.
+struct SpawnedPiece {
+    future_size: usize,
+    allocations: Option<Allocations>,
+    tasks: Vec<JoinHandle<std::io::Result<()>>>,
+}
+
+/// Report the size of each hop's future and, if we counted them, the heap
+/// allocations spawning `num_hops` hops made.
+fn report_spawn_cost(args: &Args, future_size: usize, num_hops: usize,
+                     allocations: Option<Allocations>) {
+    if args.flag_quiet {
+        return;
+    }
+    eprintln!("hop future: {} bytes", future_size);
+    if let Some(allocations) = allocations {
+        eprintln!("spawning hops: {}", allocations.per_task(num_hops));
+    }
+}
+
+fn report(args: &Args, stats: &Stats) {
+    // If adaptive warmup never settled, we have no measurements to report.
+    if !args.flag_quiet && stats.mean().is_finite() {
+        let per_hop = stats.mean() / args.flag_threads as f64;
+        eprintln!("mean {} per iteration, stddev {} ({} per task per iter, {} per hop)",
                   UsefulDuration::from(stats.mean()),
                   UsefulDuration::from(stats.population_stddev()),
-                  UsefulDuration::from(stats.mean() / args.flag_threads as f64));
+                  UsefulDuration::from(per_hop),
+                  Bandwidth::new(args.flag_payload as f64, per_hop));
     }
+}
+
+/// Shut down `head`, the writing end of the first pipe, and close
+/// `idle_writes`, the idle tasks' pipes. Wait for `tasks`, this thread's tasks,
+/// to finish, and then for `threads`, the other LocalSets' threads, to exit.
+async fn tear_down<W>(args: &Args, head: StdUnixStream, idle_writes: Vec<W>,
+                      tasks: Vec<JoinHandle<std::io::Result<()>>>,
+                      threads: Vec<std::thread::JoinHandle<()>>)
+                      -> std::io::Result<()>
+{
+    let close = || {
+        head.shutdown(Shutdown::Write)?;
+        drop(idle_writes);
+        Ok(())
+    };
+    let join = async {
+        for task in tasks {
+            let _ = task.await;
+        }
+        // This thread's tasks are all done, so it's fine to block it.
+        for thread in threads {
+            let _ = thread.join();
+        }
+    };
+    utils::tear_down_async(args.flag_threads + args.flag_idle, args.flag_quiet, close, join).await
+}
 
-    if let Some(command) = args.flag_command {
+fn run_command(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(command) = &args.flag_command {
         let command = command.replace("{pid}", &std::process::id().to_string());
         let status = Command::new("sh")
             .arg("-c")
@@ -1,38 +1,66 @@
 use async_std::task;
 use docopt::Docopt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::time::Instant;
-use utils::{Stats, UsefulDuration};
+use utils::{Allocations, Budget, Record, Stats, UsefulDuration};
 
-const USAGE: &'static str = "
+const USAGE: &str = "
 Microbenchmark of task creation overhead.
 
 Spawn a given number of asynchronous tasks. Measure how long it takes for the
 spawning process to spawn all the tasks, and how long it takes a spawned task to
 begin execution.
 
+Given `--duration TIME`, like `30s` or `500ms`, the benchmark iterates for that
+long instead of performing `--iters` iterations, and reports how many it
+completed; likewise, `--warmup-duration TIME` replaces `--warmups`.
+
+The program also reports the size of each task's future and, if built with
+the `count-allocations` cargo feature, the number and size of the heap
+allocations spawning each task makes.
+
+If `--results FILE` is given, the program appends a JSON record of its
+parameters, the machine it ran on, and its headline statistics to FILE, for
+`compare check` to examine.
+
 Usage:
-  task-creation [--tasks N] [--iters N] [--warmups N]
+  task-creation [options]
 
 Options:
   --tasks <N>     Number of tasks. [default: 10000]
   --iters <N>       Number of iterations to perform. [default: 100]
   --warmups <N>     Number of warmup iterations to perform before benchmarking.
                     [default: 10]
+  --duration <TIME>
+                    Iterate for TIME, like `30s`, instead of `--iters` times.
+  --warmup-duration <TIME>
+                    Warm up for TIME instead of `--warmups` iterations.
+  --results <FILE>  Append a record of this run's statistics to FILE.
+  --strict          Refuse to run if the environment will make results
+                    noisy.
 ";
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Args {
     flag_tasks: usize,
     flag_iters: usize,
     flag_warmups: usize,
+    flag_duration: Option<UsefulDuration>,
+    flag_warmup_duration: Option<UsefulDuration>,
+    #[serde(skip_serializing)]
+    flag_results: Option<String>,
+    #[serde(skip_serializing)]
+    flag_strict: bool,
 }
 
-fn main() {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    eprintln!("environment: {}", utils::Environment::capture());
+    utils::preflight(args.flag_strict)?;
+
     struct StartedTask {
         start_time: Instant,
         handle: task::JoinHandle<Instant>,
@@ -43,19 +71,23 @@ fn main() {
         end_time: Instant,
     }
 
+    let body = || async move { Instant::now() };
+
     let mut started = Vec::with_capacity(args.flag_tasks);
     let mut finished = Vec::with_capacity(args.flag_tasks);
 
-    eprintln!("{} tasks, {} warmups, {} iterations:", args.flag_tasks, args.flag_warmups, args.flag_iters);
+    let iters = Budget::new(args.flag_iters, args.flag_duration);
+    let warmups = Budget::new(args.flag_warmups, args.flag_warmup_duration);
+    eprintln!("{} tasks, {}, {}:", args.flag_tasks, warmups.describe("warmups"), iters);
 
     // Do a few warmup passes.
-    for _warmup in 0..args.flag_warmups {
+    for _warmup in warmups.start() {
         started.clear();
         finished.clear();
 
         for _ in 0..args.flag_tasks {
             let start_time = Instant::now();
-            let handle = task::spawn(async move { Instant::now() });
+            let handle = task::spawn(body());
             started.push(StartedTask { start_time, handle });
         }
 
@@ -69,15 +101,18 @@ fn main() {
     // Do the real passes.
     let mut creation_times = Stats::new();
     let mut started_times = Stats::new();
-    for _rep in 0..args.flag_iters {
+    let mut spawn_allocations = Allocations::now().map(|_| Allocations::default());
+    let mut countdown = iters.start();
+    for _rep in &mut countdown {
         started.clear();
         finished.clear();
 
         let start_creation = Instant::now();
         for _ in 0..args.flag_tasks {
             let start_time = Instant::now();
-            let handle = task::spawn(async move { Instant::now() });
+            let (handle, spawned) = Allocations::during(|| task::spawn(body()));
             started.push(StartedTask { start_time, handle });
+            spawn_allocations = spawn_allocations.zip(spawned).map(|(total, spawned)| total + spawned);
         }
         let end_creation = Instant::now();
         creation_times.push(UsefulDuration::from(end_creation - start_creation).into());
@@ -93,7 +128,11 @@ fn main() {
                                  UsefulDuration::from(*end_time - *start_time).into()
                              }));
     }
+    countdown.check_completed()?;
 
+    if iters.is_time() {
+        eprintln!("{}", countdown);
+    }
     eprintln!("create a task: mean {} per iter, stddev {} ({} per task)",
               UsefulDuration::from(creation_times.mean()),
               UsefulDuration::from(creation_times.population_stddev()),
@@ -101,4 +140,17 @@ fn main() {
     eprintln!("creation to body: mean {}, stddev {}",
               UsefulDuration::from(started_times.mean()),
               UsefulDuration::from(started_times.population_stddev()));
+    eprintln!("task future: {} bytes", std::mem::size_of_val(&body()));
+    if let Some(total) = spawn_allocations {
+        eprintln!("spawning: {}", total.per_task(args.flag_tasks * countdown.completed()));
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("async-creation", &args);
+        record.lower_is_better("creation per task", creation_times.mean() / args.flag_tasks as f64);
+        record.lower_is_better("creation to body", started_times.mean());
+        record.append_to(path)?;
+    }
+
+    Ok(())
 }
@@ -1,10 +1,115 @@
-use std::time::Instant;
+use docopt::Docopt;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use utils::{Stats, UsefulDuration};
+use utils::{Allocations, Bandwidth, Budget, Record, RingRate, Stats, Throughput};
+use utils::UsefulDuration;
+
+const USAGE: &str = "
+Microbenchmark of context switch overhead.
+
+Create a chain of Rust asynchronous tasks connected together by Tokio channels,
+each one repeatedly receiving a count from its upstream channel and sending it,
+incremented, to its downstream channel. One 'iteration' of the benchmark drops
+a zero in one end, and measures the time required for it to come out the other
+end.
+
+If `--in-flight K` is given, the benchmark measures throughput instead of
+latency: it keeps K counts circulating through the chain at once, and reports
+how many come out the far end per second. Each iteration collects one count and
+injects another. Since each channel holds only one value, K may not exceed the
+number of tasks.
+
+If `--ring` is given, the last task sends back to the first, forming a ring,
+and the main task stays out of the loop entirely. One count (or K, with
+`--in-flight`) circulates continuously, and a separate sampling thread measures
+how many laps the first task sees per second. `--iters` and `--warmups` are
+ignored; one sample interval is discarded as warmup.
+
+Given `--duration TIME`, like `30s` or `500ms`, the benchmark iterates for that
+long instead of performing `--iters` iterations, and reports how many it
+completed; likewise, `--warmup-duration TIME` replaces `--warmups`. In ring
+mode these set the number of samples and the warmup interval.
+
+The program reports the size of each task's future and, if built with the
+`count-allocations` cargo feature, the number and size of the heap allocations
+spawning each task makes.
+
+If `--results FILE` is given, the program appends a JSON record of its
+parameters, the machine it ran on, and its headline statistics to FILE, for
+`compare check` to examine.
+
+If `--payload BYTES` is given, each count travels with a BYTES-byte buffer, and
+the program also reports the bandwidth of each hop. The buffer is moved from
+task to task without being copied, so this shows what passing ownership costs,
+compared with `async-brigade --payload`, which copies every message into and out
+of the kernel.
+
+Finally, the program tears the chain down, and reports how long that took.
+
+Usage:
+  async-mem-brigade [options]
+
+Options:
+  --threads <N>     Number of async tasks (note: not OS threads). [default: 500]
+  --iters <N>       Number of iterations to perform. [default: 10000]
+  --warmups <N>     Number of warmup iterations to perform before benchmarking.
+                    [default: 5]
+  --duration <TIME>
+                    Iterate for TIME, like `30s`, instead of `--iters` times.
+  --warmup-duration <TIME>
+                    Warm up for TIME instead of `--warmups` iterations.
+  --in-flight <K>   Measure throughput with K counts in flight at once.
+  --ring            Circulate counts around a ring, and sample the lap rate.
+  --samples <N>     Number of lap rate samples to take in ring mode.
+                    [default: 20]
+  --sample-ms <MS>  Length of each lap rate sample, in milliseconds.
+                    [default: 100]
+  --payload <BYTES>
+                    Size of the buffer sent with each count. [default: 1]
+  --results <FILE>  Append a record of this run's statistics to FILE.
+  --strict          Refuse to run if the environment will make results
+                    noisy.
+";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Args {
+    flag_threads: usize,
+    flag_iters: usize,
+    flag_warmups: usize,
+    flag_duration: Option<UsefulDuration>,
+    flag_warmup_duration: Option<UsefulDuration>,
+    flag_in_flight: Option<usize>,
+    flag_ring: bool,
+    flag_samples: usize,
+    flag_sample_ms: u64,
+    flag_payload: usize,
+    #[serde(skip_serializing)]
+    flag_results: Option<String>,
+    #[serde(skip_serializing)]
+    flag_strict: bool,
+}
+
+/// A count, and the buffer that travels along with it.
+#[derive(Debug)]
+struct Message {
+    count: usize,
+
+    /// Never examined: it's only here to be moved along the chain.
+    _payload: Vec<u8>,
+}
+
+impl Message {
+    fn new(payload: usize) -> Message {
+        Message { count: 0, _payload: vec![b'*'; payload] }
+    }
+}
 
 struct Pipe {
-    read: mpsc::Receiver<usize>,
-    write: mpsc::Sender<usize>,
+    read: mpsc::Receiver<Message>,
+    write: mpsc::Sender<Message>,
 }
 
 fn pipe() -> Result<Pipe, std::io::Error> {
@@ -12,54 +117,200 @@ fn pipe() -> Result<Pipe, std::io::Error> {
     Ok(Pipe { read, write })
 }
 
+/// Receive a message from the end of the chain, check that it passed through
+/// every task, and reset its count for reuse.
+async fn collect(upstream_read: &mut mpsc::Receiver<Message>, num_tasks: usize) -> Message {
+    let mut message = upstream_read.recv().await.unwrap();
+    assert_eq!(message.count, num_tasks);
+    message.count = 0;
+    message
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    const NUM_TASKS: usize = 500;
-    const NUM_WARMUP_REPS: usize = 5;
-    const NUM_REPS: usize = 10000;
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    eprintln!("environment: {}", utils::Environment::capture());
+    utils::preflight(args.flag_strict)?;
 
+    match args.flag_in_flight {
+        Some(0) => Err("--in-flight must be at least 1")?,
+        Some(k) if k > args.flag_threads => Err("--in-flight may not exceed --threads")?,
+        _ => (),
+    }
+    if args.flag_ring && args.flag_threads == 0 {
+        Err("a ring needs at least one task")?;
+    }
+    if args.flag_sample_ms == 0 {
+        Err("--sample-ms must be at least 1")?;
+    }
+
+    let iters = Budget::new(args.flag_iters, args.flag_duration);
+    let warmups = Budget::new(args.flag_warmups, args.flag_warmup_duration);
+
+    let num_tasks = args.flag_threads;
+    utils::Needs { fds: 2 * (num_tasks + 1), ..Default::default() }.check()?;
+    let laps = Arc::new(AtomicUsize::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
     let Pipe { read: mut upstream_read, write: first_write} = pipe()?;
-    for _i in 0..NUM_TASKS {
+    let mut tasks = Vec::with_capacity(num_tasks);
+    let mut future_size = 0;
+    let mut spawn_allocations = Allocations::now().map(|_| Allocations::default());
+    for i in 0..num_tasks {
         let next_pipe = pipe()?;
-        let downstream_write = next_pipe.write;
-        tokio::spawn(async move {
-            // Establish 'async' block's return type. Yeah.
-            if false {
-                return Ok::<(), mpsc::error::SendError<usize>>(());
-            }
 
-            loop {
-                let n = upstream_read.recv().await.unwrap();
-                downstream_write.send(n + 1).await?;
+        // In a ring, the last task sends to the first, and the first counts
+        // laps, and checks whether it's time to tear the ring down.
+        let downstream_write = if args.flag_ring && i + 1 == num_tasks {
+            first_write.clone()
+        } else {
+            next_pipe.write
+        };
+        let ring_head = (args.flag_ring && i == 0).then(|| (laps.clone(), stop.clone()));
+
+        let future = async move {
+            while let Some(mut message) = upstream_read.recv().await {
+                if let Some((laps, stop)) = &ring_head {
+                    laps.fetch_add(1, Ordering::Relaxed);
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                }
+                message.count += 1;
+                if downstream_write.send(message).await.is_err() {
+                    break;
+                }
             }
-        });
+        };
+        future_size = std::mem::size_of_val(&future);
+
+        // Count only the allocations `spawn` makes, not the channel's.
+        let (task, spawned) = Allocations::during(|| tokio::spawn(future));
+        tasks.push(task);
+        spawn_allocations = spawn_allocations.zip(spawned).map(|(total, spawned)| total + spawned);
         upstream_read = next_pipe.read;
     }
-
-    // Warm up.
-    for _i in 0..NUM_WARMUP_REPS {
-        first_write.send(0).await?;
-        assert_eq!(upstream_read.recv().await, Some(NUM_TASKS));
+    println!("task future: {} bytes", future_size);
+    if let Some(total) = spawn_allocations {
+        println!("spawning: {}", total.per_task(num_tasks));
     }
 
-    let mut stats = Stats::new();
-    for _i in 0..NUM_REPS {
+    if args.flag_ring {
+        let in_flight = args.flag_in_flight.unwrap_or(1);
+        for _i in 0..in_flight {
+            first_write.send(Message::new(args.flag_payload)).await?;
+        }
+
+        let interval = Duration::from_millis(args.flag_sample_ms);
+        let warmup = args.flag_warmup_duration.map_or(interval, Duration::from);
+        let samples = Budget::new(args.flag_samples, args.flag_duration)
+            .iterations_at(1000.0 / args.flag_sample_ms as f64);
+        let passes = tokio::task::spawn_blocking(move || {
+            utils::sample_rate(&laps, warmup, interval, samples)
+        })
+            .await?;
+        let rate = RingRate { passes, tasks: num_tasks, in_flight, payload: args.flag_payload };
+
+        println!("{} samples of {}ms, {} tasks in a ring, {} in flight, {}",
+                 samples, args.flag_sample_ms, num_tasks, in_flight, rate);
+
+        if let Some(path) = &args.flag_results {
+            let mut record = Record::new("async-mem-brigade", &args);
+            record.higher_is_better("laps/s", rate.laps_per_sec());
+            record.append_to(path)?;
+        }
+    } else if let Some(in_flight) = args.flag_in_flight {
+        for _i in 0..in_flight {
+            first_write.send(Message::new(args.flag_payload)).await?;
+        }
+
+        // Warm up.
+        for _i in warmups.start() {
+            let message = collect(&mut upstream_read, num_tasks).await;
+            first_write.send(message).await?;
+        }
+
         let start = Instant::now();
-        first_write.send(0).await?;
-        assert_eq!(upstream_read.recv().await, Some(NUM_TASKS));
+        let mut countdown = iters.start();
+        for _i in &mut countdown {
+            let message = collect(&mut upstream_read, num_tasks).await;
+            first_write.send(message).await?;
+        }
         let end = Instant::now();
 
-        stats.push(UsefulDuration::from(end - start).into());
+        // Collect the counts still in flight.
+        for _i in 0..in_flight {
+            collect(&mut upstream_read, num_tasks).await;
+        }
+
+        let throughput = Throughput {
+            messages: countdown.completed(),
+            elapsed: (end - start).as_secs_f64(),
+            tasks: num_tasks,
+            payload: args.flag_payload,
+        };
+        println!("{} iterations, {} tasks, {} in flight, {}",
+                 throughput.messages, num_tasks, in_flight, throughput);
+
+        if let Some(path) = &args.flag_results {
+            let mut record = Record::new("async-mem-brigade", &args);
+            record.higher_is_better("messages/s", throughput.messages_per_sec());
+            record.append_to(path)?;
+        }
+    } else {
+        let mut message = Message::new(args.flag_payload);
+
+        // Warm up.
+        for _i in warmups.start() {
+            first_write.send(message).await?;
+            message = collect(&mut upstream_read, num_tasks).await;
+        }
+
+        let mut stats = Stats::new();
+        let mut countdown = iters.start();
+        for _i in &mut countdown {
+            let start = Instant::now();
+            first_write.send(message).await?;
+            message = collect(&mut upstream_read, num_tasks).await;
+            let end = Instant::now();
+
+            stats.push(UsefulDuration::from(end - start).into());
+        }
+        countdown.check_completed()?;
+
+        let per_hop = stats.mean() / num_tasks as f64;
+        println!("{} iterations, {} tasks, mean {} per iteration, stddev {} ({} per task per iter, {} per hop)",
+                 countdown.completed(), num_tasks,
+                 UsefulDuration::from(stats.mean()),
+                 UsefulDuration::from(stats.population_stddev()),
+                 UsefulDuration::from(per_hop),
+                 Bandwidth::new(args.flag_payload as f64, per_hop));
+
+        if let Some(path) = &args.flag_results {
+            let mut record = Record::new("async-mem-brigade", &args);
+            record.lower_is_better("mean", stats.mean());
+            record.append_to(path)?;
+        }
     }
 
-    println!("{} iterations, {} tasks, mean {} per iteration, stddev {} ({} per task per iter)",
-             NUM_REPS, NUM_TASKS,
-             UsefulDuration::from(stats.mean()),
-             UsefulDuration::from(stats.population_stddev()),
-             UsefulDuration::from(stats.mean() / NUM_TASKS as f64));
+    // Once the first task's upstream channel closes, each task's exit closes
+    // the next one's. Dropping `first_write` doesn't close a ring's channel,
+    // since the last task has a sender too, so the first task checks `stop`.
+    let close = || {
+        stop.store(true, Ordering::Relaxed);
+        drop(first_write);
+        drop(upstream_read);
+        Ok(())
+    };
+    let join = async {
+        for task in tasks {
+            task.await?;
+        }
+        Ok::<_, tokio::task::JoinError>(())
+    };
+    utils::tear_down_async(num_tasks, false, close, join).await??;
 
-    // Otherwise, Tokio blocks waiting for other tasks to finish. I don't want
-    // to risk introducing noise by adding shutdown logic to them, so just exit
-    // the entire process.
-    std::process::exit(0);
+    Ok(())
 }
@@ -0,0 +1,206 @@
+use docopt::Docopt;
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use utils::{Budget, Links, Record, Stats, Topology, UsefulDuration};
+
+const USAGE: &str = "
+Microbenchmark of context switch overhead in fan-out/fan-in topologies.
+
+Create a graph of Rust asynchronous tasks connected together by pipes. Each
+task repeatedly reads a single byte from each of its upstream pipes, and then
+writes a single byte to each of its downstream pipes. One 'iteration' of the
+benchmark drops a byte into the graph's entry, and measures the time required
+for a byte to come out of its exit.
+
+The `--topology` option selects the shape of the graph:
+
+- `fan`: one task scatters to `--width` workers, and another gathers their
+  results.
+
+- `tree`: a request flows down a complete `--arity`-ary tree of `--depth`
+  levels, and the leaves' replies are aggregated back up a mirror-image tree.
+
+- `dag`: a random DAG of `--nodes` tasks, generated from `--seed`, in which
+  each task reads from up to `--max-fan-in` earlier tasks.
+
+The benchmark reports the number of hops on the graph's critical path (the
+longest path from entry to exit), and the total number of switches per
+iteration (one per pipe).
+
+Given `--duration TIME`, like `30s` or `500ms`, the benchmark iterates for that
+long instead of performing `--iters` iterations, and reports how many it
+completed; likewise, `--warmup-duration TIME` replaces `--warmups`.
+
+If `--results FILE` is given, the program appends a JSON record of its
+parameters, the machine it ran on, and its headline statistics to FILE, for
+`compare check` to examine.
+
+If `--command COMMAND` is given, then the program runs `COMMAND` before exiting.
+This gives an opportunity to measure the program's memory use. If `COMMAND`
+contains the string `{pid}`, each occurrence is replaced with this program's
+process ID.
+
+Usage:
+  async-topology [options]
+
+Options:
+  --topology <T>     Graph shape: `fan`, `tree`, or `dag`. [default: fan]
+  --width <N>        Number of workers in a fan. [default: 100]
+  --arity <K>        Number of children of each interior tree node. [default: 2]
+  --depth <D>        Number of levels in a tree. [default: 8]
+  --nodes <N>        Number of tasks in a random DAG. [default: 500]
+  --max-fan-in <K>   Maximum number of upstream tasks in a random DAG.
+                     [default: 3]
+  --seed <S>         Seed for generating a random DAG. [default: 1]
+  --iters <N>        Number of iterations to perform. [default: 10000]
+  --warmups <N>      Number of warmup iterations to perform before benchmarking.
+                     [default: 100]
+  --duration <TIME>  Iterate for TIME, like `30s`, instead of `--iters` times.
+  --warmup-duration <TIME>
+                     Warm up for TIME instead of `--warmups` iterations.
+  --results <FILE>   Append a record of this run's statistics to FILE.
+  --strict           Refuse to run if the environment will make results
+                     noisy.
+  --command <CMD>    Command to run before exiting.
+  --quiet            Don't print time measurements.
+";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Args {
+    flag_topology: Shape,
+    flag_width: usize,
+    flag_arity: usize,
+    flag_depth: usize,
+    flag_nodes: usize,
+    flag_max_fan_in: usize,
+    flag_seed: u64,
+    flag_iters: usize,
+    flag_warmups: usize,
+    flag_duration: Option<UsefulDuration>,
+    flag_warmup_duration: Option<UsefulDuration>,
+    #[serde(skip_serializing)]
+    flag_results: Option<String>,
+    #[serde(skip_serializing)]
+    flag_strict: bool,
+    #[serde(skip_serializing)]
+    flag_command: Option<String>,
+    #[serde(skip_serializing)]
+    flag_quiet: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Shape {
+    Fan,
+    Tree,
+    Dag,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    if !args.flag_quiet {
+        eprintln!("environment: {}", utils::Environment::capture());
+    }
+    utils::preflight(args.flag_strict)?;
+
+    if args.flag_width == 0 || args.flag_arity == 0 || args.flag_depth == 0
+        || args.flag_nodes == 0 || args.flag_max_fan_in == 0
+    {
+        Err("graph dimensions must be at least 1")?;
+    }
+
+    let topology = match args.flag_topology {
+        Shape::Fan => Topology::fan(args.flag_width),
+        Shape::Tree => Topology::tree(args.flag_arity, args.flag_depth)?,
+        Shape::Dag => Topology::random_dag(args.flag_nodes, args.flag_max_fan_in, args.flag_seed),
+    };
+    let critical_path = topology.critical_path();
+
+    let iters = Budget::new(args.flag_iters, args.flag_duration);
+    if !args.flag_quiet {
+        eprintln!("{:?}: {} tasks, {} switches per iteration, critical path {} hops, {}:",
+                  args.flag_topology, topology.nodes.len(), topology.num_links,
+                  critical_path, iters);
+    }
+
+    utils::Needs { fds: 2 * topology.num_links, ..Default::default() }.check()?;
+
+    let Links { input: mut first_write, output: mut last_read, nodes } =
+        topology.links(UnixStream::pair)?;
+
+    for (mut upstream, mut downstream) in nodes {
+        tokio::spawn(async move {
+            let mut buf = [0_u8; 1];
+
+            // Establish 'async' block's return type. Yeah.
+            if false {
+                return Ok::<(), std::io::Error>(());
+            }
+
+            loop {
+                for upstream_read in &mut upstream {
+                    upstream_read.read_exact(&mut buf).await?;
+                }
+                for downstream_write in &mut downstream {
+                    downstream_write.write_all(&buf).await?;
+                }
+            }
+        });
+    }
+
+    let mut buf = [0_u8; 1];
+
+    // Warm up.
+    for _i in Budget::new(args.flag_warmups, args.flag_warmup_duration).start() {
+        first_write.write_all(b"*").await?;
+        last_read.read_exact(&mut buf).await?;
+    }
+
+    let mut stats = Stats::new();
+    let mut countdown = iters.start();
+    for _i in &mut countdown {
+        let start = Instant::now();
+        first_write.write_all(b"*").await?;
+        last_read.read_exact(&mut buf).await?;
+        let end = Instant::now();
+
+        stats.push(UsefulDuration::from(end - start).into());
+    }
+    countdown.check_completed()?;
+
+    if !args.flag_quiet {
+        if iters.is_time() {
+            eprintln!("{}", countdown);
+        }
+        eprintln!("mean {} per iteration, stddev {} ({} per critical hop, {} per switch)",
+                  UsefulDuration::from(stats.mean()),
+                  UsefulDuration::from(stats.population_stddev()),
+                  UsefulDuration::from(stats.mean() / critical_path as f64),
+                  UsefulDuration::from(stats.mean() / topology.num_links as f64));
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("async-topology", &args);
+        record.lower_is_better("mean", stats.mean());
+        record.append_to(path)?;
+    }
+
+    if let Some(command) = args.flag_command {
+        let command = command.replace("{pid}", &std::process::id().to_string());
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .status()?;
+        if !status.success() {
+            Err(format!("child exited with status: {}", status))?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,179 @@
+use docopt::Docopt;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use utils::{Better, Comparison, Record, Samples, UsefulDuration};
+
+const USAGE: &str = "
+Statistical comparison of two sets of latency samples.
+
+Read the per-iteration latencies that two benchmark runs wrote with
+`--samples-out`, and decide whether the second (B) really differs from the
+first (A), or whether the difference is noise. For example:
+
+    thread-brigade --samples-out threads.txt
+    async-brigade --samples-out async.txt
+    compare threads.txt async.txt
+
+The program reports the difference (B - A) and ratio (B / A) of the means, each
+with a 95% confidence interval estimated by bootstrap resampling, and the
+p-values of Welch's t-test and the Mann-Whitney U test. B counts as
+significantly faster or slower only if the confidence interval of the
+difference excludes zero and the Mann-Whitney p-value is below 0.05.
+
+The `compare check` form reads a results file that benchmarks appended to with
+`--results`, and checks the newest run of each benchmark, set of parameters and
+machine against the earliest run with the same key, its baseline (or, with
+`--previous`, against the run just before it). It reports how each statistic
+changed, and exits with a non-zero status if any got worse by more than the
+`--threshold` fraction. A statistic whose baseline was zero has regressed if
+it moved in the wrong direction at all. For example:
+
+    thread-brigade --results results.jsonl
+    (make changes)
+    thread-brigade --results results.jsonl
+    compare check results.jsonl
+
+Usage:
+  compare check [options] <results>
+  compare [options] <a> <b>
+
+Options:
+  --resamples <N>    Number of bootstrap resamples. [default: 2000]
+  --threshold <REL>  Relative change beyond which a statistic has regressed.
+                     [default: 0.05]
+  --previous         Compare against the previous run, not the earliest.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    cmd_check: bool,
+    arg_results: String,
+    arg_a: String,
+    arg_b: String,
+    flag_resamples: usize,
+    flag_threshold: f64,
+    flag_previous: bool,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    if args.cmd_check {
+        if !check(&args)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let a = read_samples(&args.arg_a)?;
+    let b = read_samples(&args.arg_b)?;
+
+    println!("a: {}", args.arg_a);
+    println!("b: {}", args.arg_b);
+    print!("{}", Comparison::new(a.values(), b.values(), args.flag_resamples));
+
+    Ok(())
+}
+
+fn read_samples(path: &str) -> Result<Samples, Box<dyn std::error::Error>> {
+    let samples = Samples::read(BufReader::new(File::open(path)?))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    if samples.len() < 2 {
+        Err(format!("{}: need at least two samples to compare", path))?;
+    }
+    Ok(samples)
+}
+
+/// Check the newest run in each group of comparable records in the results
+/// file against its baseline. Return false if any statistic regressed.
+fn check(args: &Args) -> Result<bool, Box<dyn std::error::Error>> {
+    if args.flag_threshold < 0.0 {
+        Err("--threshold must not be negative")?;
+    }
+
+    let (records, skipped) = utils::read_records(&args.arg_results)
+        .map_err(|e| format!("{}: {}", args.arg_results, e))?;
+    for problem in &skipped {
+        eprintln!("warning: {}: {}; skipping", args.arg_results, problem);
+    }
+    let mut groups: BTreeMap<_, Vec<&Record>> = BTreeMap::new();
+    for record in &records {
+        groups.entry(record.key()).or_default().push(record);
+    }
+
+    let mut regressions = 0;
+    for ((benchmark, params, machine), runs) in &groups {
+        println!("{} {}", benchmark, params);
+        println!("  on {}", machine);
+        let (new, older) = runs.split_last().unwrap();
+        let baseline = match (older.first(), older.last()) {
+            (Some(first), Some(last)) => if args.flag_previous { last } else { first },
+            _ => {
+                println!("  only one run, nothing to compare");
+                continue;
+            }
+        };
+        if let (Some(before), Some(after)) = (&baseline.environment, &new.environment) {
+            for change in after.changes_since(before) {
+                println!("  note: {}", change);
+            }
+        }
+
+        for (name, stat) in &new.stats {
+            let old = match baseline.stats.get(name) {
+                Some(old) => old,
+                None => {
+                    println!("  {}: {} (new)", name, show(stat.value, stat.better));
+                    continue;
+                }
+            };
+            // A relative change from zero is meaningless, so compare the
+            // values directly, and count any move in the wrong direction.
+            let (change, regressed) = if old.value == 0.0 {
+                let worse = match stat.better {
+                    Better::Lower => stat.value > 0.0,
+                    Better::Higher => stat.value < 0.0,
+                };
+                let sign = if stat.value < 0.0 { "-" } else { "+" };
+                (format!("{}{} from zero", sign, show(stat.value.abs(), stat.better)), worse)
+            } else {
+                let change = (stat.value - old.value) / old.value;
+                let worse = match stat.better {
+                    Better::Lower => change,
+                    Better::Higher => -change,
+                };
+                (format!("{:+.1}%", change * 100.0), worse.is_nan() || worse > args.flag_threshold)
+            };
+            let verdict = if regressed {
+                regressions += 1;
+                "  REGRESSION"
+            } else {
+                ""
+            };
+            println!("  {}: {} -> {} ({}){}",
+                     name, show(old.value, old.better), show(stat.value, stat.better),
+                     change, verdict);
+        }
+    }
+
+    if regressions > 0 {
+        println!("{} regressions beyond {:.1}%",
+                 regressions, args.flag_threshold * 100.0);
+    } else {
+        println!("no regressions beyond {:.1}%", args.flag_threshold * 100.0);
+    }
+    Ok(regressions == 0)
+}
+
+/// Format a statistic's value. Statistics for which lower is better are
+/// times; the others are rates.
+fn show(value: f64, better: Better) -> String {
+    match better {
+        Better::Lower => UsefulDuration::from(value).to_string(),
+        Better::Higher => format!("{:.1}", value),
+    }
+}
@@ -0,0 +1,16 @@
+#!/usr/bin/env bash
+
+set -eu
+
+if ! [ -f Cargo.toml ]; then
+    echo "Run in top-level directory of coroutine-brigade package." >&2
+    exit 1
+fi
+
+cargo build --release "$@"
+
+echo -e "num coroutines\tvirtual KiB\tresident KiB"
+for ((n=1000; n <= 10000; n += 500)); do
+    ../target/release/coroutine-brigade --quiet --iters 10 --threads $n --command 'pmap -x {pid}' \
+    | awk -v num_threads=$n '/^total/ { print num_threads "\t" $3 "\t" $4 }'
+done
@@ -0,0 +1,172 @@
+use docopt::Docopt;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::process::Command;
+use std::rc::Rc;
+use std::time::Instant;
+use utils::coroutine::{self, Channel};
+use utils::{Budget, Record, Stats, UsefulDuration};
+
+const USAGE: &str = "
+Microbenchmark of context switch overhead.
+
+Create a chain of stackful coroutines connected together by channels, each one
+repeatedly receiving a value from its upstream channel and sending it to its
+downstream channel. One 'iteration' of the benchmark drops a value in one end,
+and measures the time required for it to come out the other end.
+
+The coroutines are switched in user space with `swapcontext`, by a simple
+single-threaded scheduler. Each has its own `mmap`ped stack, which the kernel
+populates only as the coroutine touches it.
+
+Given `--duration TIME`, like `30s` or `500ms`, the benchmark iterates for that
+long instead of performing `--iters` iterations, and reports how many it
+completed; likewise, `--warmup-duration TIME` replaces `--warmups`.
+
+If `--results FILE` is given, the program appends a JSON record of its
+parameters, the machine it ran on, and its headline statistics to FILE, for
+`compare check` to examine.
+
+If `--command COMMAND` is given, then the program runs `COMMAND` before exiting.
+This gives an opportunity to measure the program's memory use. If `COMMAND`
+contains the string `{pid}`, each occurrence is replaced with this program's
+process ID.
+
+Finally, the program tears the chain down, and reports how long that took.
+
+Usage:
+  coroutine-brigade [options]
+
+Options:
+  --threads <N>         Number of coroutines. [default: 500]
+  --iters <N>           Number of iterations to perform. [default: 10000]
+  --warmups <N>         Number of warmup iterations to perform before
+                        benchmarking. [default: 100]
+  --duration <TIME>     Iterate for TIME, like `30s`, instead of `--iters`
+                        times.
+  --warmup-duration <TIME>
+                        Warm up for TIME instead of `--warmups` iterations.
+  --stack-size <BYTES>  Size of each coroutine's stack. [default: 65536]
+  --results <FILE>      Append a record of this run's statistics to FILE.
+  --strict              Refuse to run if the environment will make results
+                        noisy.
+  --command <CMD>       Command to run before tearing down.
+  --quiet               Don't print time measurements.
+";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Args {
+    flag_threads: usize,
+    flag_iters: usize,
+    flag_warmups: usize,
+    flag_duration: Option<UsefulDuration>,
+    flag_warmup_duration: Option<UsefulDuration>,
+    flag_stack_size: usize,
+    #[serde(skip_serializing)]
+    flag_results: Option<String>,
+    #[serde(skip_serializing)]
+    flag_strict: bool,
+    #[serde(skip_serializing)]
+    flag_command: Option<String>,
+    #[serde(skip_serializing)]
+    flag_quiet: bool,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    if !args.flag_quiet {
+        eprintln!("environment: {}", utils::Environment::capture());
+    }
+    utils::preflight(args.flag_strict)?;
+
+    let iters = Budget::new(args.flag_iters, args.flag_duration);
+    let warmups = Budget::new(args.flag_warmups, args.flag_warmup_duration);
+    if !args.flag_quiet {
+        eprintln!("{} coroutines, {}:", args.flag_threads, iters);
+    }
+
+    // Each coroutine's stack and guard page are separate map areas.
+    utils::Needs { map_areas: 2 * (args.flag_threads + 1), ..Default::default() }.check()?;
+
+    let first_write = Channel::new();
+    let head = first_write.clone();
+    let mut upstream_read = first_write.clone();
+    for _i in 0..args.flag_threads {
+        let downstream_write = Channel::new();
+        let next_read = downstream_write.clone();
+        coroutine::spawn(args.flag_stack_size, move || {
+            while let Some(n) = upstream_read.recv() {
+                downstream_write.send(n + 1);
+            }
+            downstream_write.close();
+        })?;
+        upstream_read = next_read;
+    }
+
+    // The driver is a coroutine too, so that it can block in `recv`.
+    let stats = Rc::new(RefCell::new(Stats::new()));
+    let countdown = Rc::new(RefCell::new(None));
+    let (driver_stats, driver_countdown) = (stats.clone(), countdown.clone());
+    let num_tasks = args.flag_threads;
+    coroutine::spawn(args.flag_stack_size, move || {
+        // Warm up.
+        for _i in warmups.start() {
+            first_write.send(0);
+            assert_eq!(upstream_read.recv(), Some(num_tasks));
+        }
+
+        let mut stats = driver_stats.borrow_mut();
+        let mut countdown = iters.start();
+        for _i in &mut countdown {
+            let start = Instant::now();
+            first_write.send(0);
+            assert_eq!(upstream_read.recv(), Some(num_tasks));
+            let end = Instant::now();
+
+            stats.push(UsefulDuration::from(end - start).into());
+        }
+        *driver_countdown.borrow_mut() = Some(countdown);
+    })?;
+
+    coroutine::run();
+    countdown.borrow().as_ref().unwrap().check_completed()?;
+
+    let stats = stats.borrow();
+    if !args.flag_quiet {
+        if iters.is_time() {
+            eprintln!("{}", countdown.borrow().as_ref().unwrap());
+        }
+        eprintln!("mean {} per iteration, stddev {} ({} per task per iter)",
+                  UsefulDuration::from(stats.mean()),
+                  UsefulDuration::from(stats.population_stddev()),
+                  UsefulDuration::from(stats.mean() / args.flag_threads as f64));
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("coroutine-brigade", &args);
+        record.lower_is_better("mean", stats.mean());
+        record.append_to(path)?;
+    }
+
+    if let Some(command) = &args.flag_command {
+        let command = command.replace("{pid}", &std::process::id().to_string());
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .status()?;
+        if !status.success() {
+            Err(format!("child exited with status: {}", status))?;
+        }
+    }
+
+    let close = || {
+        head.close();
+        Ok(())
+    };
+    utils::tear_down(args.flag_threads, args.flag_quiet, close, coroutine::run)?;
+
+    Ok(())
+}
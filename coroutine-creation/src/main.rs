@@ -0,0 +1,162 @@
+use docopt::Docopt;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Instant;
+use utils::coroutine;
+use utils::{Budget, Record, Stats, UsefulDuration};
+
+const USAGE: &str = "
+Microbenchmark of task creation overhead.
+
+Spawn a given number of stackful coroutines. Measure how long it takes for the
+spawning process to spawn all the coroutines, and how long it takes a spawned
+coroutine to begin execution.
+
+Coroutines only run when the spawning thread hands control to the scheduler,
+which it does after spawning them all, so the 'creation to body' time includes
+the time spent creating the rest of the batch.
+
+Given `--duration TIME`, like `30s` or `500ms`, the benchmark iterates for that
+long instead of performing `--iters` iterations, and reports how many it
+completed; likewise, `--warmup-duration TIME` replaces `--warmups`.
+
+If `--results FILE` is given, the program appends a JSON record of its
+parameters, the machine it ran on, and its headline statistics to FILE, for
+`compare check` to examine.
+
+Usage:
+  coroutine-creation [options]
+
+Options:
+  --tasks <N>           Number of coroutines. [default: 10000]
+  --iters <N>           Number of iterations to perform. [default: 100]
+  --warmups <N>         Number of warmup iterations to perform before
+                        benchmarking. [default: 10]
+  --duration <TIME>     Iterate for TIME, like `30s`, instead of `--iters`
+                        times.
+  --warmup-duration <TIME>
+                        Warm up for TIME instead of `--warmups` iterations.
+  --results <FILE>      Append a record of this run's statistics to FILE.
+  --strict              Refuse to run if the environment will make results
+                        noisy.
+  --stack-size <BYTES>  Size of each coroutine's stack. [default: 65536]
+";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Args {
+    flag_tasks: usize,
+    flag_iters: usize,
+    flag_warmups: usize,
+    flag_duration: Option<UsefulDuration>,
+    flag_warmup_duration: Option<UsefulDuration>,
+    #[serde(skip_serializing)]
+    flag_results: Option<String>,
+    #[serde(skip_serializing)]
+    flag_strict: bool,
+    flag_stack_size: usize,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    eprintln!("environment: {}", utils::Environment::capture());
+    utils::preflight(args.flag_strict)?;
+
+    struct StartedTask {
+        start_time: Instant,
+        end_time: Rc<Cell<Option<Instant>>>,
+    }
+
+    struct FinishedTask {
+        start_time: Instant,
+        end_time: Instant,
+    }
+
+    let mut started = Vec::with_capacity(args.flag_tasks);
+    let mut finished = Vec::with_capacity(args.flag_tasks);
+
+    let iters = Budget::new(args.flag_iters, args.flag_duration);
+    let warmups = Budget::new(args.flag_warmups, args.flag_warmup_duration);
+    eprintln!("{} tasks, {}, {}:", args.flag_tasks, warmups.describe("warmups"), iters);
+
+    // Each coroutine's stack and guard page are separate map areas.
+    utils::Needs { map_areas: 2 * args.flag_tasks, ..Default::default() }.check()?;
+
+    let spawn = |started: &mut Vec<StartedTask>| -> std::io::Result<()> {
+        let start_time = Instant::now();
+        let end_time = Rc::new(Cell::new(None));
+        let body_end_time = end_time.clone();
+        coroutine::spawn(args.flag_stack_size, move || body_end_time.set(Some(Instant::now())))?;
+        started.push(StartedTask { start_time, end_time });
+        Ok(())
+    };
+
+    // Do a few warmup passes.
+    for _warmup in warmups.start() {
+        started.clear();
+        finished.clear();
+
+        for _ in 0..args.flag_tasks {
+            spawn(&mut started)?;
+        }
+
+        coroutine::run();
+        finished.extend(started.drain(..)
+                        .map(|StartedTask { start_time, end_time }| {
+                            let end_time = end_time.get().unwrap();
+                            FinishedTask { start_time, end_time }
+                        }));
+    }
+
+    // Do the real passes.
+    let mut creation_times = Stats::new();
+    let mut started_times = Stats::new();
+    let mut countdown = iters.start();
+    for _rep in &mut countdown {
+        started.clear();
+        finished.clear();
+
+        let start_creation = Instant::now();
+        for _ in 0..args.flag_tasks {
+            spawn(&mut started)?;
+        }
+        let end_creation = Instant::now();
+        creation_times.push(UsefulDuration::from(end_creation - start_creation).into());
+
+        coroutine::run();
+        finished.extend(started.drain(..)
+                        .map(|StartedTask { start_time, end_time }| {
+                            let end_time = end_time.get().unwrap();
+                            FinishedTask { start_time, end_time }
+                        }));
+
+        started_times.extend(finished.iter()
+                             .map(|FinishedTask { start_time, end_time }| {
+                                 UsefulDuration::from(*end_time - *start_time).into()
+                             }));
+    }
+    countdown.check_completed()?;
+
+    if iters.is_time() {
+        eprintln!("{}", countdown);
+    }
+    eprintln!("create a task: mean {} per iter, stddev {} ({} per task)",
+              UsefulDuration::from(creation_times.mean()),
+              UsefulDuration::from(creation_times.population_stddev()),
+              UsefulDuration::from(creation_times.mean() / args.flag_tasks as f64));
+    eprintln!("creation to body: mean {}, stddev {}",
+              UsefulDuration::from(started_times.mean()),
+              UsefulDuration::from(started_times.population_stddev()));
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("coroutine-creation", &args);
+        record.lower_is_better("creation per task", creation_times.mean() / args.flag_tasks as f64);
+        record.lower_is_better("creation to body", started_times.mean());
+        record.append_to(path)?;
+    }
+
+    Ok(())
+}
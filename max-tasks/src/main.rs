@@ -0,0 +1,252 @@
+use docopt::Docopt;
+use serde::Deserialize;
+use std::io;
+use std::sync::{Arc, RwLock};
+use utils::{coroutine, Usage};
+
+const USAGE: &str = "
+Find how many tasks of a given kind this machine can run at once.
+
+Create threads, async tasks, processes, or coroutines, keeping each one alive,
+until creating another fails. Then report how many there were, the error, how
+many file descriptors, memory map areas, threads, cgroup pids and how much
+memory were in use at that point, and which limit was probably responsible.
+
+Spawning an async task never fails: when allocation fails, Tokio aborts. So
+rather than let the kernel's out-of-memory killer pick a victim, the program
+stops when less than `--reserve` MiB of memory is available, whatever kind of
+task it's creating. `--limit` caps the count outright.
+
+Usage:
+  max-tasks [options] <kind>
+
+where <kind> is `threads`, `async`, `processes`, or `coroutines`.
+
+Options:
+  --limit <N>           Stop after creating N tasks. [default: 10000000]
+  --reserve <MiB>       Stop when less than this much memory is available.
+                        [default: 256]
+  --stack-size <BYTES>  Stack size for threads and coroutines. By default,
+                        threads get Rust's default, and coroutines 64KiB.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_kind: Kind,
+    flag_limit: usize,
+    flag_reserve: u64,
+    flag_stack_size: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+enum Kind {
+    #[serde(rename = "threads")]
+    Threads,
+    #[serde(rename = "async")]
+    Async,
+    #[serde(rename = "processes")]
+    Processes,
+    #[serde(rename = "coroutines")]
+    Coroutines,
+}
+
+impl Kind {
+    fn noun(self) -> &'static str {
+        match self {
+            Kind::Threads => "threads",
+            Kind::Async => "async tasks",
+            Kind::Processes => "processes",
+            Kind::Coroutines => "coroutines",
+        }
+    }
+}
+
+/// Why a probe stopped creating tasks.
+enum Stop {
+    /// Creating another task failed with the given error.
+    Failed(io::Error),
+
+    /// Available memory fell below `--reserve`.
+    Memory,
+
+    /// We created `--limit` tasks.
+    Limit,
+
+    /// We were about to exceed `vm.max_map_count`, whose value is given.
+    /// Creating a thread past this point makes the standard library abort
+    /// the whole process, rather than return an error.
+    MapAreas(usize),
+}
+
+/// The outcome of a probe: how many tasks it created, why it stopped, and the
+/// resources in use at that point.
+struct Probe {
+    count: usize,
+    stop: Stop,
+    usage: Usage,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    eprintln!("environment: {}", utils::Environment::capture());
+    let noun = args.arg_kind.noun();
+    eprintln!("creating {} until it fails, or {} have been created:", noun, args.flag_limit);
+
+    let probe = match args.arg_kind {
+        Kind::Threads => threads(&args),
+        Kind::Async => async_tasks(&args)?,
+        Kind::Processes => processes(&args),
+        Kind::Coroutines => coroutines(&args),
+    };
+
+    match &probe.stop {
+        Stop::Failed(error) => {
+            println!("ceiling: {} {}", probe.count, noun);
+            println!("creating another failed: {}", error);
+        }
+        Stop::Memory => {
+            println!("ceiling: at least {} {}", probe.count, noun);
+            println!("stopped with less than {} MiB of memory available", args.flag_reserve);
+        }
+        Stop::Limit => {
+            println!("ceiling: at least {} {}", probe.count, noun);
+            println!("stopped after creating --limit {}", noun);
+        }
+        Stop::MapAreas(max) => {
+            println!("ceiling: {} {}", probe.count, noun);
+            println!("stopped short of the memory map area limit, vm.max_map_count = {}; \
+                      past it, a new thread aborts the process", max);
+        }
+    }
+    println!("at that point:");
+    for line in probe.usage.to_string().lines() {
+        println!("    {}", line);
+    }
+    match &probe.stop {
+        Stop::Failed(error) => println!("probable limit: {}", probe.usage.probable_limit(error, probe.count)),
+        Stop::Memory => println!("probable limit: memory"),
+        Stop::MapAreas(_) => println!("probable limit: vm.max_map_count"),
+        Stop::Limit => {}
+    }
+
+    Ok(())
+}
+
+/// Call `create` until it fails, we reach `--limit`, or memory runs low.
+fn probe<F>(args: &Args, mut create: F) -> Probe
+where
+    F: FnMut() -> io::Result<()>,
+{
+    // No kind of task we create uses more memory map areas than this.
+    const MOST_MAP_AREAS: usize = utils::THREAD_MAP_AREAS;
+
+    let reserve = args.flag_reserve << 20;
+    let max_map_count = std::fs::read_to_string("/proc/sys/vm/max_map_count").ok()
+        .and_then(|text| text.trim().parse::<usize>().ok());
+    let mut count = 0;
+    let mut next_check = 0;
+    loop {
+        if count == args.flag_limit {
+            return Probe { count, stop: Stop::Limit, usage: Usage::now() };
+        }
+        // Taking a `Usage` snapshot takes a while, so don't check every time,
+        // but do check more often as we approach the map area limit.
+        if count == next_check {
+            let usage = Usage::now();
+            if usage.mem_available.is_some_and(|free| free < reserve) {
+                return Probe { count, stop: Stop::Memory, usage };
+            }
+            let mut interval = 1000;
+            if let Some(max) = max_map_count {
+                let room = max.saturating_sub(usage.map_areas) / MOST_MAP_AREAS;
+                if room == 0 {
+                    return Probe { count, stop: Stop::MapAreas(max), usage };
+                }
+                interval = interval.min((room / 2).max(1));
+            }
+            next_check = count + interval;
+        }
+        if let Err(error) = create() {
+            return Probe { count, stop: Stop::Failed(error), usage: Usage::now() };
+        }
+        count += 1;
+    }
+}
+
+/// Spawn threads that block on a lock the main thread holds until the probe
+/// is over.
+fn threads(args: &Args) -> Probe {
+    let gate = Arc::new(RwLock::new(()));
+    let closed = gate.write().unwrap();
+    let mut handles = vec![];
+    let result = probe(args, || {
+        let mut builder = std::thread::Builder::new();
+        if let Some(size) = args.flag_stack_size {
+            builder = builder.stack_size(size);
+        }
+        let gate = gate.clone();
+        handles.push(builder.spawn(move || drop(gate.read()))?);
+        Ok(())
+    });
+
+    drop(closed);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    result
+}
+
+/// Spawn Tokio tasks that never complete on a single-threaded runtime, which
+/// never gets a chance to run them.
+fn async_tasks(args: &Args) -> io::Result<Probe> {
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    let _context = runtime.enter();
+    Ok(probe(args, || {
+        tokio::spawn(std::future::pending::<()>());
+        Ok(())
+    }))
+}
+
+/// Fork child processes that wait for a signal, and kill them when the probe
+/// is over.
+fn processes(args: &Args) -> Probe {
+    let mut children = vec![];
+    let result = probe(args, || {
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => unsafe {
+                // Only async-signal-safe calls are allowed here. Make sure we
+                // don't outlive the parent, should it be killed.
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                loop {
+                    libc::pause();
+                }
+            },
+            child => {
+                children.push(child);
+                Ok(())
+            }
+        }
+    });
+
+    for &child in &children {
+        unsafe {
+            libc::kill(child, libc::SIGKILL);
+            libc::waitpid(child, std::ptr::null_mut(), 0);
+        }
+    }
+    result
+}
+
+/// Spawn coroutines, which don't run until we call `coroutine::run`.
+fn coroutines(args: &Args) -> Probe {
+    let stack_size = args.flag_stack_size.unwrap_or(64 * 1024);
+    let result = probe(args, || coroutine::spawn(stack_size, || ()).map(drop));
+
+    // Let them all run to completion, which frees their stacks.
+    coroutine::run();
+    result
+}
@@ -1,7 +1,83 @@
-use std::time::Instant;
+use docopt::Docopt;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
 use std::os::unix::net::UnixStream;
 use std::io::prelude::*;
-use utils::{Stats, UsefulDuration};
+use utils::{Adaptive, Budget, Record, Samples, Teardown, UsefulDuration};
+
+const USAGE: &str = "
+Microbenchmark of pipe I/O overhead alone.
+
+Create a chain of pipes, but have a single thread loop through them all, doing
+the reads and writes itself. One 'iteration' of the benchmark drops a byte in
+one end, carries it all the way down the chain, and measures the time required
+for it to come out the other end. This gives a baseline cost for the I/O
+operations, which can be subtracted from the other brigades' times.
+
+If `--adaptive` is given, the benchmark chooses the numbers of warmups and
+iterations itself, ignoring `--warmups` and `--iters`. It warms up until the
+medians of two consecutive `--window`-sized batches of iterations agree to
+within 5%, and then measures until the 95% confidence interval of the mean (or,
+with `--quantile Q`, of the Q quantile, like 0.99) is within `--target` of the
+statistic, or until the `--duration` budget (10s by default) runs out, and
+reports whether it converged.
+
+Given `--duration TIME`, like `30s` or `500ms`, the benchmark iterates for that
+long instead of performing `--iters` iterations; likewise, `--warmup-duration
+TIME` replaces `--warmups`.
+
+If `--samples-out FILE` is given, the program writes each iteration's latency
+to FILE, one per line in seconds, for the `compare` program to analyze.
+
+If `--results FILE` is given, the program appends a JSON record of its
+parameters, the machine it ran on, and its headline statistics to FILE, for
+`compare check` to examine.
+
+Finally, the program closes its pipes, as a baseline for the other teardowns.
+
+Usage:
+  one-thread-brigade [options]
+
+Options:
+  --threads <N>     Number of pipes in the chain. [default: 500]
+  --iters <N>       Number of iterations to perform. [default: 10000]
+  --warmups <N>     Number of warmup iterations to perform before benchmarking.
+                    [default: 5]
+  --duration <TIME>
+                    Iterate for TIME, like `30s`, instead of `--iters` times.
+  --warmup-duration <TIME>
+                    Warm up for TIME instead of `--warmups` iterations.
+  --adaptive        Choose the numbers of warmups and iterations automatically.
+  --target <REL>    Relative error at which adaptive measurement stops.
+                    [default: 0.01]
+  --quantile <Q>    Make the adaptive target apply to the Q quantile.
+  --window <N>      Number of iterations per adaptive warmup batch.
+                    [default: 100]
+  --samples-out <FILE>
+                    Write each iteration's latency to FILE.
+  --results <FILE>  Append a record of this run's statistics to FILE.
+  --strict          Refuse to run if the environment will make results
+                    noisy.
+";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Args {
+    flag_threads: usize,
+    flag_iters: usize,
+    flag_warmups: usize,
+    flag_duration: Option<UsefulDuration>,
+    flag_warmup_duration: Option<UsefulDuration>,
+    flag_adaptive: bool,
+    flag_target: f64,
+    flag_quantile: Option<f64>,
+    flag_window: usize,
+    #[serde(skip_serializing)]
+    flag_samples_out: Option<String>,
+    #[serde(skip_serializing)]
+    flag_results: Option<String>,
+    #[serde(skip_serializing)]
+    flag_strict: bool,
+}
 
 struct Pipe {
     read: UnixStream,
@@ -14,13 +90,27 @@ fn pipe() -> Result<Pipe, std::io::Error> {
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    const NUM_TASKS: usize = 500;
-    const NUM_WARMUP_REPS: usize = 5;
-    const NUM_REPS: usize = 10000;
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    eprintln!("environment: {}", utils::Environment::capture());
+    utils::preflight(args.flag_strict)?;
+
+    if args.flag_adaptive {
+        if args.flag_window < 2 || args.flag_target <= 0.0 {
+            Err("--window must be at least 2, and --target must be positive")?;
+        }
+        if !matches!(args.flag_quantile, None | Some(0.0..=1.0)) {
+            Err("--quantile must be between 0 and 1")?;
+        }
+    }
 
+    let num_tasks = args.flag_threads;
+    utils::Needs { fds: 2 * (num_tasks + 1), ..Default::default() }.check()?;
     let Pipe { read: mut upstream_read, write: mut first_write} = pipe()?;
     let mut pipes = Vec::new();
-    for _i in 0..NUM_TASKS {
+    for _i in 0..num_tasks {
         let next_pipe = pipe()?;
         pipes.push(Pipe {
             read: upstream_read,
@@ -40,30 +130,72 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let mut buf = [0_u8; 1];
-
-    // Warm up.
-    for _i in 0..NUM_WARMUP_REPS {
+    let mut iteration = || -> Result<(), std::io::Error> {
         first_write.write_all(b"*")?;
         brigade(&mut pipes)?;
-        upstream_read.read(&mut buf)?;
-    }
+        upstream_read.read_exact(&mut buf)?;
+        Ok(())
+    };
 
-    let mut stats = Stats::new();
-    for _i in 0..NUM_REPS {
-        let start = Instant::now();
-        first_write.write_all(b"*")?;
-        brigade(&mut pipes)?;
-        upstream_read.read(&mut buf)?;
-        let end = Instant::now();
+    let mut samples = if args.flag_adaptive {
+        let mut sampler = Adaptive {
+            window: args.flag_window,
+            stability: 0.05,
+            target: args.flag_target,
+            quantile: args.flag_quantile,
+            budget: args.flag_duration.map_or(Duration::from_secs(10), Duration::from),
+        }.start();
+        while sampler.wants_more() {
+            let start = Instant::now();
+            iteration()?;
+            sampler.push((Instant::now() - start).as_secs_f64());
+        }
 
-        stats.push(UsefulDuration::from(end - start).into());
-    }
+        let outcome = sampler.finish();
+        println!("{}", outcome);
+        if outcome.samples.is_empty() {
+            return Ok(());
+        }
+        outcome.samples
+    } else {
+        // Warm up.
+        for _i in Budget::new(args.flag_warmups, args.flag_warmup_duration).start() {
+            iteration()?;
+        }
 
+        let mut samples = Samples::new();
+        let mut countdown = Budget::new(args.flag_iters, args.flag_duration).start();
+        for _i in &mut countdown {
+            let start = Instant::now();
+            iteration()?;
+            let end = Instant::now();
+
+            samples.push(UsefulDuration::from(end - start).into());
+        }
+        countdown.check_completed()?;
+        samples
+    };
+
+    let stats = samples.stats();
     println!("{} iterations, {} tasks, mean {} per iteration, stddev {} ({} per task per iter)",
-             NUM_REPS, NUM_TASKS,
+             samples.len(), num_tasks,
              UsefulDuration::from(stats.mean()),
              UsefulDuration::from(stats.population_stddev()),
-             UsefulDuration::from(stats.mean() / NUM_TASKS as f64));
+             UsefulDuration::from(stats.mean() / num_tasks as f64));
+
+    if let Some(path) = &args.flag_samples_out {
+        samples.write(std::io::BufWriter::new(std::fs::File::create(path)?))?;
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("one-thread-brigade", &args);
+        record.latencies(&mut samples);
+        record.append_to(path)?;
+    }
+
+    let teardown = Teardown::start(num_tasks);
+    drop((first_write, pipes, upstream_read));
+    println!("{}", teardown.finish());
 
     Ok(())
 }
@@ -0,0 +1,319 @@
+use docopt::Docopt;
+use serde::{Deserialize, Serialize};
+use std::io::prelude::*;
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use utils::{Budget, Record, UsefulDuration};
+
+const USAGE: &str = "
+Microbenchmark of many concurrent request/response pairs.
+
+Create P independent pairs of tasks, each pair bouncing a token back and forth
+between its two members. Measure the aggregate number of round trips per
+second completed by all the pairs together, for each P in the `--pairs` list.
+Unlike the bucket brigades, many tasks are runnable at once, so this exercises
+the scalability of the scheduler.
+
+The `--variant` option selects how the pairs are implemented:
+
+- `thread`: each member is a thread, and the pair talks over a socketpair.
+
+- `async-pipe`: each member is a Tokio task, talking over a socketpair.
+
+- `async-channel`: each member is a Tokio task, talking over a pair of Tokio
+  channels.
+
+Each pair performs its warmup round trips, and then all pairs wait for each
+other before the measured round trips begin.
+
+Given `--duration TIME`, like `30s` or `500ms`, each pair bounces its token for
+that long instead of performing `--round-trips` round trips, and the program
+reports how many round trips the pairs completed in all; likewise,
+`--warmup-duration TIME` replaces `--warmups`.
+
+If `--results FILE` is given, the program appends a JSON record of its
+parameters, the machine it ran on, and the round trip rate for each number of
+pairs to FILE, for `compare check` to examine.
+
+Usage:
+  ping-pong [options]
+
+Options:
+  --variant <V>       How to implement the pairs: `thread`, `async-pipe`, or
+                      `async-channel`. [default: thread]
+  --pairs <LIST>      Comma-separated list of numbers of pairs to try.
+                      [default: 1,10,100,1000]
+  --round-trips <N>   Number of round trips each pair performs. [default: 1000]
+  --warmups <N>       Number of warmup round trips each pair performs before
+                      benchmarking. [default: 100]
+  --duration <TIME>   Bounce each token for TIME, like `30s`, instead of
+                      `--round-trips` times.
+  --warmup-duration <TIME>
+                      Warm up for TIME instead of `--warmups` round trips.
+  --results <FILE>    Append a record of this run's statistics to FILE.
+  --strict            Refuse to run if the environment will make results
+                      noisy.
+";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Args {
+    flag_variant: Variant,
+    flag_pairs: String,
+    flag_round_trips: usize,
+    flag_warmups: usize,
+    flag_duration: Option<UsefulDuration>,
+    flag_warmup_duration: Option<UsefulDuration>,
+    #[serde(skip_serializing)]
+    flag_results: Option<String>,
+    #[serde(skip_serializing)]
+    flag_strict: bool,
+}
+
+impl Args {
+    fn round_trips(&self) -> Budget {
+        Budget::new(self.flag_round_trips, self.flag_duration)
+    }
+
+    fn warmups(&self) -> Budget {
+        Budget::new(self.flag_warmups, self.flag_warmup_duration)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+enum Variant {
+    #[serde(rename = "thread")]
+    Thread,
+    #[serde(rename = "async-pipe")]
+    AsyncPipe,
+    #[serde(rename = "async-channel")]
+    AsyncChannel,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    eprintln!("environment: {}", utils::Environment::capture());
+    utils::preflight(args.flag_strict)?;
+
+    let pair_counts = args.flag_pairs.split(',')
+        .map(|p| p.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("bad --pairs list: {}", e))?;
+    if pair_counts.contains(&0) {
+        Err("--pairs entries must be at least 1")?;
+    }
+    if args.round_trips() == Budget::Iterations(0) {
+        Err("--round-trips must be at least 1")?;
+    }
+
+    eprintln!("{:?}, {} per pair:", args.flag_variant, args.round_trips().describe("round trips"));
+
+    let most_pairs = pair_counts.iter().copied().max().unwrap_or(0);
+    let (threads, fds) = match args.flag_variant {
+        Variant::Thread => (2 * most_pairs, 2 * most_pairs),
+        Variant::AsyncPipe => (0, 2 * most_pairs),
+        Variant::AsyncChannel => (0, 0),
+    };
+    utils::Needs { threads, fds, ..Default::default() }.check()?;
+
+    let mut rates = Vec::with_capacity(pair_counts.len());
+    let runtime = tokio::runtime::Runtime::new()?;
+    for &pairs in &pair_counts {
+        let (elapsed, round_trips) = match args.flag_variant {
+            Variant::Thread => thread_pairs(&args, pairs)?,
+            Variant::AsyncPipe => runtime.block_on(async_pipe_pairs(&args, pairs))?,
+            Variant::AsyncChannel => runtime.block_on(async_channel_pairs(&args, pairs)),
+        };
+        if round_trips == 0 {
+            Err(format!("no round trips completed with {} pairs in a budget of {}",
+                        pairs, args.round_trips().describe("round trips")))?;
+        }
+
+        if args.flag_duration.is_some() {
+            eprintln!("{:6} pairs: completed {} round trips in {}",
+                      pairs, round_trips, UsefulDuration::from(elapsed));
+        }
+        let elapsed = elapsed.as_secs_f64();
+        eprintln!("{:6} pairs: {:.0} round trips/s ({} per round trip per pair)",
+                  pairs,
+                  round_trips as f64 / elapsed,
+                  UsefulDuration::from(elapsed * pairs as f64 / round_trips as f64));
+        rates.push((pairs, round_trips as f64 / elapsed));
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("ping-pong", &args);
+        for (pairs, rate) in rates {
+            record.higher_is_better(&format!("round trips/s with {} pairs", pairs), rate);
+        }
+        record.append_to(path)?;
+    }
+
+    Ok(())
+}
+
+/// Run `pairs` pairs of threads, and return the time taken by the measured
+/// round trips, and how many the pairs completed in all.
+fn thread_pairs(args: &Args, pairs: usize) -> Result<(Duration, usize), std::io::Error> {
+    let barrier = Arc::new(Barrier::new(pairs + 1));
+    let mut echoes = Vec::with_capacity(pairs);
+    let mut pings = Vec::with_capacity(pairs);
+    for _i in 0..pairs {
+        let (mut ping, mut pong) = std::os::unix::net::UnixStream::pair()?;
+
+        // Echo bytes back until the other end is closed.
+        echoes.push(std::thread::Builder::new()
+                     .stack_size(1024 * 1024)
+                     .spawn(move || -> Result<(), std::io::Error> {
+            let mut buf = [0_u8; 1];
+            while pong.read(&mut buf)? == 1 {
+                pong.write_all(&buf)?;
+            }
+            Ok(())
+        })?);
+
+        let barrier = barrier.clone();
+        let (warmups, round_trips) = (args.warmups(), args.round_trips());
+        pings.push(std::thread::Builder::new()
+                   .stack_size(1024 * 1024)
+                   .spawn(move || -> Result<usize, std::io::Error> {
+            let mut buf = [0_u8; 1];
+            let warmed = warmups.start().try_for_each(|_i| {
+                ping.write_all(b"*")?;
+                ping.read_exact(&mut buf)
+            });
+            // Reach the barrier even if warming up failed, or the other
+            // threads would wait for us forever.
+            barrier.wait();
+            warmed?;
+            let mut countdown = round_trips.start();
+            for _i in &mut countdown {
+                ping.write_all(b"*")?;
+                ping.read_exact(&mut buf)?;
+            }
+            Ok(countdown.completed())
+        })?);
+    }
+
+    barrier.wait();
+    let start = Instant::now();
+    let mut completed = 0;
+    for handle in pings {
+        completed += handle.join().unwrap()?;
+    }
+    let elapsed = start.elapsed();
+    for handle in echoes {
+        handle.join().unwrap()?;
+    }
+    Ok((elapsed, completed))
+}
+
+/// Run `pairs` pairs of async tasks talking over socketpairs, and return the
+/// time taken by the measured round trips, and how many the pairs completed in
+/// all.
+async fn async_pipe_pairs(args: &Args, pairs: usize) -> Result<(Duration, usize), std::io::Error> {
+    let barrier = Arc::new(tokio::sync::Barrier::new(pairs + 1));
+    let mut echoes = Vec::with_capacity(pairs);
+    let mut pings = Vec::with_capacity(pairs);
+    for _i in 0..pairs {
+        let (mut ping, mut pong) = tokio::net::UnixStream::pair()?;
+
+        // Echo bytes back until the other end is closed.
+        echoes.push(tokio::spawn(async move {
+            let mut buf = [0_u8; 1];
+            while pong.read(&mut buf).await? == 1 {
+                pong.write_all(&buf).await?;
+            }
+            Ok::<(), std::io::Error>(())
+        }));
+
+        let barrier = barrier.clone();
+        let (warmups, round_trips) = (args.warmups(), args.round_trips());
+        pings.push(tokio::spawn(async move {
+            let mut buf = [0_u8; 1];
+            let warmed = async {
+                for _i in warmups.start() {
+                    ping.write_all(b"*").await?;
+                    ping.read_exact(&mut buf).await?;
+                }
+                Ok::<(), std::io::Error>(())
+            }.await;
+            // Reach the barrier even if warming up failed, or the other tasks
+            // would wait for us forever.
+            barrier.wait().await;
+            warmed?;
+            let mut countdown = round_trips.start();
+            for _i in &mut countdown {
+                ping.write_all(b"*").await?;
+                ping.read_exact(&mut buf).await?;
+            }
+            Ok::<usize, std::io::Error>(countdown.completed())
+        }));
+    }
+
+    barrier.wait().await;
+    let start = Instant::now();
+    let mut completed = 0;
+    for handle in pings {
+        completed += handle.await.unwrap()?;
+    }
+    let elapsed = start.elapsed();
+    for handle in echoes {
+        handle.await.unwrap()?;
+    }
+    Ok((elapsed, completed))
+}
+
+/// Run `pairs` pairs of async tasks talking over Tokio channels, and return
+/// the time taken by the measured round trips, and how many the pairs
+/// completed in all.
+async fn async_channel_pairs(args: &Args, pairs: usize) -> (Duration, usize) {
+    let barrier = Arc::new(tokio::sync::Barrier::new(pairs + 1));
+    let mut echoes = Vec::with_capacity(pairs);
+    let mut pings = Vec::with_capacity(pairs);
+    for _i in 0..pairs {
+        let (ping_tx, mut pong_rx) = mpsc::channel::<usize>(1);
+        let (pong_tx, mut ping_rx) = mpsc::channel::<usize>(1);
+
+        // Echo values back until the other end is dropped.
+        echoes.push(tokio::spawn(async move {
+            while let Some(n) = pong_rx.recv().await {
+                if pong_tx.send(n).await.is_err() {
+                    break;
+                }
+            }
+        }));
+
+        let barrier = barrier.clone();
+        let (warmups, round_trips) = (args.warmups(), args.round_trips());
+        pings.push(tokio::spawn(async move {
+            for i in warmups.start() {
+                ping_tx.send(i).await.unwrap();
+                assert_eq!(ping_rx.recv().await, Some(i));
+            }
+            barrier.wait().await;
+            let mut countdown = round_trips.start();
+            for i in &mut countdown {
+                ping_tx.send(i).await.unwrap();
+                assert_eq!(ping_rx.recv().await, Some(i));
+            }
+            countdown.completed()
+        }));
+    }
+
+    barrier.wait().await;
+    let start = Instant::now();
+    let mut completed = 0;
+    for handle in pings {
+        completed += handle.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    for handle in echoes {
+        handle.await.unwrap();
+    }
+    (elapsed, completed)
+}
@@ -0,0 +1,285 @@
+use docopt::Docopt;
+use serde::{Deserialize, Serialize};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::process::Command;
+use std::sync::mpsc;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::task::JoinHandle;
+use utils::{Budget, Record, Stats, UsefulDuration};
+
+const USAGE: &str = "
+Microbenchmark of cross-core wakeups in a thread-per-core async runtime.
+
+Start one OS thread per shard, each pinned to its own CPU and running its own
+current-thread Tokio runtime. Then build a chain of asynchronous tasks connected
+together by pipes, each one repeatedly reading a single byte from its upstream
+pipe and writing it to its downstream pipe, and assign each task to a shard. One
+'iteration' of the benchmark drops a byte in one end, and measures the time
+required for it to come out the other end.
+
+The `--mapping` option says how tasks are assigned to shards:
+
+- `contiguous` cuts the chain into one block of consecutive tasks per shard, so
+  the byte only crosses cores at block boundaries.
+
+- `round-robin` deals the tasks out to the shards like cards, so that (given
+  more than one shard) every hop crosses from one core to another.
+
+The driver that injects and collects the byte runs on the first shard.
+
+Given `--duration TIME`, like `30s` or `500ms`, the benchmark iterates for that
+long instead of performing `--iters` iterations, and reports how many it
+completed; likewise, `--warmup-duration TIME` replaces `--warmups`.
+
+If `--results FILE` is given, the program appends a JSON record of its
+parameters, the machine it ran on, and its headline statistics to FILE, for
+`compare check` to examine.
+
+If `--command COMMAND` is given, then the program runs `COMMAND` before exiting.
+This gives an opportunity to measure the program's memory use. If `COMMAND`
+contains the string `{pid}`, each occurrence is replaced with this program's
+process ID.
+
+Finally, the program tears the chain down, and reports how long that took.
+
+Usage:
+  sharded-brigade [options]
+
+Options:
+  --threads <N>        Number of async tasks (note: not OS threads). [default: 500]
+  --iters <N>          Number of iterations to perform. [default: 10000]
+  --warmups <N>        Number of warmup iterations to perform before benchmarking.
+                       [default: 100]
+  --duration <TIME>    Iterate for TIME, like `30s`, instead of `--iters` times.
+  --warmup-duration <TIME>
+                       Warm up for TIME instead of `--warmups` iterations.
+  --shards <N>         Number of shards. If zero, use one shard per CPU this
+                       process may run on. [default: 0]
+  --mapping <MAPPING>  How to assign tasks to shards: `contiguous` or
+                       `round-robin`. [default: contiguous]
+  --results <FILE>     Append a record of this run's statistics to FILE.
+  --strict             Refuse to run if the environment will make results
+                       noisy.
+  --command <CMD>      Command to run before tearing down.
+  --quiet              Don't print time measurements.
+";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Args {
+    flag_threads: usize,
+    flag_iters: usize,
+    flag_warmups: usize,
+    flag_duration: Option<UsefulDuration>,
+    flag_warmup_duration: Option<UsefulDuration>,
+    flag_shards: usize,
+    flag_mapping: Mapping,
+    #[serde(skip_serializing)]
+    flag_results: Option<String>,
+    #[serde(skip_serializing)]
+    flag_strict: bool,
+    #[serde(skip_serializing)]
+    flag_command: Option<String>,
+    #[serde(skip_serializing)]
+    flag_quiet: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+enum Mapping {
+    #[serde(rename = "contiguous")]
+    Contiguous,
+    #[serde(rename = "round-robin")]
+    RoundRobin,
+}
+
+impl Mapping {
+    /// Return the shard that should run task `task` of `num_tasks`.
+    fn shard(self, task: usize, num_tasks: usize, num_shards: usize) -> usize {
+        match self {
+            Mapping::Contiguous => task * num_shards / num_tasks,
+            Mapping::RoundRobin => task % num_shards,
+        }
+    }
+}
+
+/// The body of each task in the brigade: pass bytes from upstream to
+/// downstream until either pipe is closed.
+async fn hop(mut upstream_read: UnixStream, mut downstream_write: UnixStream) -> std::io::Result<()> {
+    let mut buf = [0_u8; 1];
+    loop {
+        assert_eq!(upstream_read.read_exact(&mut buf).await?, 1);
+        downstream_write.write_all(&buf).await?;
+    }
+}
+
+/// Spawn a task on the current runtime for each hop in `hops`, and return
+/// their handles.
+///
+/// Tokio sockets belong to the reactor of the runtime that created them, so the
+/// chain is built from standard sockets, which each shard converts itself.
+fn spawn_hops(hops: Vec<(StdUnixStream, StdUnixStream)>)
+              -> std::io::Result<Vec<JoinHandle<std::io::Result<()>>>> {
+    hops.into_iter()
+        .map(|(read, write)| {
+            Ok(tokio::spawn(hop(UnixStream::from_std(read)?, UnixStream::from_std(write)?)))
+        })
+        .collect()
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    if !args.flag_quiet {
+        eprintln!("environment: {}", utils::Environment::capture());
+    }
+    utils::preflight(args.flag_strict)?;
+
+    let cpus = utils::available_cpus()?;
+    let num_shards = if args.flag_shards == 0 { cpus.len() } else { args.flag_shards };
+    let num_tasks = args.flag_threads;
+    if num_tasks == 0 {
+        Err("--threads must be at least 1")?;
+    }
+    utils::Needs { threads: num_shards, fds: 2 * (num_tasks + 1), ..Default::default() }.check()?;
+
+    // Build the chain, and sort the hops into shards.
+    let mut shards: Vec<Vec<(StdUnixStream, StdUnixStream)>> =
+        (0..num_shards).map(|_| vec![]).collect();
+    let (mut upstream_read, first_write) = StdUnixStream::pair()?;
+    let head = first_write.try_clone()?;
+    first_write.set_nonblocking(true)?;
+    for task in 0..num_tasks {
+        let (next_read, downstream_write) = StdUnixStream::pair()?;
+        upstream_read.set_nonblocking(true)?;
+        downstream_write.set_nonblocking(true)?;
+        let shard = args.flag_mapping.shard(task, num_tasks, num_shards);
+        shards[shard].push((upstream_read, downstream_write));
+        upstream_read = next_read;
+    }
+    upstream_read.set_nonblocking(true)?;
+
+    // Count the links whose ends are on different shards. The driver is on
+    // shard zero.
+    let shard_of = |task| args.flag_mapping.shard(task, num_tasks, num_shards);
+    let crossings = (shard_of(0) != 0) as usize
+        + (1..num_tasks).filter(|&task| shard_of(task - 1) != shard_of(task)).count()
+        + (shard_of(num_tasks - 1) != 0) as usize;
+
+    let iters = Budget::new(args.flag_iters, args.flag_duration);
+    let warmups = Budget::new(args.flag_warmups, args.flag_warmup_duration);
+    if !args.flag_quiet {
+        eprintln!("{} tasks on {} shards ({:?}), {} of {} hops cross shards, {}:",
+                  num_tasks, num_shards, args.flag_mapping,
+                  crossings, num_tasks + 1, iters);
+    }
+
+    // Start a thread for every shard but the first, and wait for them all to
+    // have spawned their tasks. Each thread exits once its tasks have.
+    let mut shards = shards.into_iter().enumerate();
+    let (_, main_shard) = shards.next().unwrap();
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let mut threads = Vec::with_capacity(num_shards - 1);
+    for (shard, hops) in shards {
+        let cpu = cpus[shard % cpus.len()];
+        let ready_tx = ready_tx.clone();
+        threads.push(std::thread::spawn(move || {
+            let result = (|| -> std::io::Result<_> {
+                utils::pin_current_thread(cpu)?;
+                tokio::runtime::Builder::new_current_thread().enable_all().build()
+            })();
+            let runtime = match result {
+                Ok(runtime) => runtime,
+                Err(e) => return ready_tx.send(Err(e)).unwrap(),
+            };
+            runtime.block_on(async move {
+                let tasks = match spawn_hops(hops) {
+                    Ok(tasks) => tasks,
+                    Err(e) => return ready_tx.send(Err(e)).unwrap(),
+                };
+                ready_tx.send(Ok(())).unwrap();
+                for task in tasks {
+                    let _ = task.await;
+                }
+            })
+        }));
+    }
+    for _shard in 1..num_shards {
+        ready_rx.recv()??;
+    }
+
+    utils::pin_current_thread(cpus[0])?;
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(async move {
+        let tasks = spawn_hops(main_shard)?;
+        let mut first_write = UnixStream::from_std(first_write)?;
+        let mut upstream_read = UnixStream::from_std(upstream_read)?;
+        let mut buf = [0_u8; 1];
+
+        // Warm up.
+        for _i in warmups.start() {
+            first_write.write_all(b"*").await?;
+            upstream_read.read_exact(&mut buf).await?;
+        }
+
+        let mut stats = Stats::new();
+        let mut countdown = iters.start();
+        for _i in &mut countdown {
+            let start = Instant::now();
+            first_write.write_all(b"*").await?;
+            upstream_read.read_exact(&mut buf).await?;
+            let end = Instant::now();
+
+            stats.push(UsefulDuration::from(end - start).into());
+        }
+        countdown.check_completed()?;
+
+        if !args.flag_quiet {
+            if iters.is_time() {
+                eprintln!("{}", countdown);
+            }
+            eprintln!("mean {} per iteration, stddev {} ({} per task per iter)",
+                      UsefulDuration::from(stats.mean()),
+                      UsefulDuration::from(stats.population_stddev()),
+                      UsefulDuration::from(stats.mean() / num_tasks as f64));
+        }
+
+        if let Some(path) = &args.flag_results {
+            let mut record = Record::new("sharded-brigade", &args);
+            record.lower_is_better("mean", stats.mean());
+            record.append_to(path)?;
+        }
+
+        if let Some(command) = &args.flag_command {
+            let command = command.replace("{pid}", &std::process::id().to_string());
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .status()?;
+            if !status.success() {
+                Err(format!("child exited with status: {}", status))?;
+            }
+        }
+
+        let close = || {
+            head.shutdown(Shutdown::Write)?;
+            drop((first_write, upstream_read));
+            Ok(())
+        };
+        let join = async {
+            for task in tasks {
+                let _ = task.await;
+            }
+            // Once this shard's tasks are done, it's fine to block.
+            for thread in threads {
+                let _ = thread.join();
+            }
+        };
+        utils::tear_down_async(num_tasks, args.flag_quiet, close, join).await?;
+
+        Ok(())
+    })
+}
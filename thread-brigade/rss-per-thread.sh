@@ -7,7 +7,7 @@ if ! [ -f Cargo.toml ]; then
     exit 1
 fi
 
-cargo build --release
+cargo build --release "$@"
 
 echo -e "num threads\tvirtual KiB\tresident KiB"
 for ((n=100; n <= 1000; n += 50)); do
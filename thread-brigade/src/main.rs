@@ -1,12 +1,18 @@
 use docopt::Docopt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::io::prelude::*;
+use std::net::Shutdown;
 use std::os::unix::net::UnixStream;
 use std::process::Command;
-use std::time::Instant;
-use utils::{Stats, UsefulDuration};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use utils::{Adaptive, Arrivals, Bandwidth, Budget, HopEvent, OpenLoop, Samples};
+use utils::{parse_rates, Record, RingRate, SharedTrace, TraceReport, TraceRing};
+use utils::{Throughput, UsefulDuration, Work};
 
-const USAGE: &'static str = "
+const USAGE: &str = "
 Microbenchmark of context switch overhead.
 
 Create a chain of threads connected together by pipes, each one repeatedly
@@ -14,29 +20,176 @@ reading a single byte from its upstream pipe and writing it to its downstream
 pipe. One 'iteration' of the benchmark drops a byte in one end, and measures the
 time required for it to come out the other end.
 
+If `--adaptive` is given, the benchmark chooses the numbers of warmups and
+iterations itself, ignoring `--warmups` and `--iters`. It warms up until the
+medians of two consecutive `--window`-sized batches of iterations agree to
+within 5%, and then measures until the 95% confidence interval of the mean (or,
+with `--quantile Q`, of the Q quantile, like 0.99) is within `--target` of the
+statistic, or until the `--duration` budget (10s by default) runs out, and
+reports whether it converged.
+
+Given `--duration TIME`, like `30s` or `500ms`, the benchmark iterates for that
+long instead of performing `--iters` iterations, and reports how many it
+completed; likewise, `--warmup-duration TIME` replaces `--warmups`. In ring
+mode these set the number of samples and the warmup interval, and in open-loop
+mode they set the number of bytes injected at each rate.
+
+If `--samples-out FILE` is given, the program writes each iteration's latency
+to FILE, one per line in seconds, for the `compare` program to analyze.
+
+If `--results FILE` is given, the program appends a JSON record of its
+parameters, the machine it ran on, and its headline statistics to FILE, for
+`compare check` to examine.
+
+If `--in-flight K` is given, the benchmark measures throughput instead of
+latency: it keeps K bytes circulating through the chain at once, and reports
+how many come out the far end per second. Each iteration collects one byte and
+injects another.
+
+If `--ring` is given, the last thread writes back to the first, forming a ring,
+and the main thread stays out of the loop entirely. One byte (or K, with
+`--in-flight`) circulates continuously, and the main thread samples how many
+laps the first thread sees per second. `--iters` and `--warmups` are ignored;
+one sample interval (or `--warmup-duration`) is discarded as warmup.
+
+If `--trace N` is given, each thread records timestamps in a preallocated ring
+buffer as it receives and sends each byte, and after measuring latency the
+program reports the distribution of per-hop wake latencies over the last N
+iterations, and the hops that were slowest to wake, along with how often they
+crossed from one CPU to another. This only works in the default latency mode.
+With `--trace-out FILE`, the program also writes the last few traced
+iterations to FILE in the Chrome Trace Event format, for viewing in Perfetto or
+`chrome://tracing`: each thread gets a track showing when it was blocked in
+`read`, running, and writing, and which CPU it was on.
+
+If `--rates LIST` is given, the benchmark drives the chain open-loop: for each
+rate in LIST, in bytes per second, a timer thread injects `--iters` bytes (plus
+`--warmups` more) on a schedule, without waiting for earlier bytes to come out,
+and the main thread collects them. Each byte's latency is measured from the
+time it was scheduled to be sent, so that queueing delays aren't hidden when
+the injector falls behind. The `--arrivals` option chooses between evenly
+spaced (`fixed`) and exponentially distributed (`poisson`) gaps. The program
+reports latency percentiles at each offered load.
+
+If `--payload BYTES` is given, each message is BYTES long instead of a single
+byte, and the program also reports the bandwidth of each hop, to show where the
+cost of copying the message into and out of the kernel starts to outweigh the
+cost of the context switch. Messages too large for the socket buffers can
+deadlock a ring in which every thread holds one.
+
+If `--idle N` is given, the program also starts N idle threads, each blocked
+reading its own pipe, to see how a large idle population affects the active
+chain.
+
+To see how the difference between threads and async tasks changes once the
+tasks do something, `--work-ns NS` makes each thread spin on the CPU for NS
+nanoseconds every time it passes a byte along, and `--working-set BYTES` makes
+it write BYTES bytes of its own stack, as a request handler's local variables
+would occupy.
+
+Each thread gets a `--stack-size` byte stack. If `--paint-stacks` is given,
+each thread fills its stack with a pattern when it starts, and once the chain
+is torn down after measuring, the program reports how many bytes of stack the
+threads actually touched, as a basis for choosing a stack size. Painting makes
+every thread's whole stack resident, so don't combine it with `--command` to
+measure memory use.
+
 If `--measure COMMAND` is given, then the program runs `COMMAND` before exiting.
 This gives an opportunity to measure the program's memory use. If `COMMAND`
 contains the string `{pid}`, each occurrence is replaced with this program's
 process ID.
 
+Finally, the program tears the chain down, and reports how long that took.
+
 Usage:
-  thread-brigade [--threads N] [--iters N] [--warmups N] [--command COMMAND] [--quiet]
+  thread-brigade [options]
 
 Options:
   --threads <N>     Number of threads. [default: 500]
   --iters <N>       Number of iterations to perform. [default: 10000]
   --warmups <N>     Number of warmup iterations to perform before benchmarking.
                     [default: 100]
-  --command <CMD>   Command to run before exiting.
+  --duration <TIME>
+                    Iterate for TIME, like `30s`, instead of `--iters` times.
+  --warmup-duration <TIME>
+                    Warm up for TIME instead of `--warmups` iterations.
+  --adaptive        Choose the numbers of warmups and iterations automatically.
+  --target <REL>    Relative error at which adaptive measurement stops.
+                    [default: 0.01]
+  --quantile <Q>    Make the adaptive target apply to the Q quantile.
+  --window <N>      Number of iterations per adaptive warmup batch.
+                    [default: 100]
+  --samples-out <FILE>
+                    Write each iteration's latency to FILE.
+  --results <FILE>  Append a record of this run's statistics to FILE.
+  --strict          Refuse to run if the environment will make results
+                    noisy.
+  --in-flight <K>   Measure throughput with K bytes in flight at once.
+  --ring            Circulate bytes around a ring, and sample the lap rate.
+  --samples <N>     Number of lap rate samples to take in ring mode.
+                    [default: 20]
+  --sample-ms <MS>  Length of each lap rate sample, in milliseconds.
+                    [default: 100]
+  --trace <N>       Trace each hop of the last N iterations.
+  --trace-out <FILE>
+                    Write traced iterations to FILE as Chrome Trace JSON.
+  --trace-out-iters <K>
+                    Number of iterations to write to the trace file.
+                    [default: 3]
+  --rates <LIST>    Drive the chain open-loop at each of these comma-separated
+                    rates, in bytes per second.
+  --arrivals <A>    Spacing of open-loop arrivals: `fixed` or `poisson`.
+                    [default: poisson]
+  --payload <BYTES>
+                    Size of each message, in bytes. [default: 1]
+  --idle <N>        Number of additional idle threads. [default: 0]
+  --work-ns <NS>    Nanoseconds of CPU work per hop. [default: 0]
+  --working-set <BYTES>
+                    Bytes of stack each thread writes per hop. [default: 0]
+  --stack-size <BYTES>
+                    Size of each thread's stack. [default: 1048576]
+  --paint-stacks    Report how much of its stack each thread used.
+  --command <CMD>   Command to run before tearing down.
   --quiet           Don't print time measurements.
 ";
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Args {
     flag_threads: usize,
     flag_iters: usize,
     flag_warmups: usize,
+    flag_duration: Option<UsefulDuration>,
+    flag_warmup_duration: Option<UsefulDuration>,
+    flag_adaptive: bool,
+    flag_target: f64,
+    flag_quantile: Option<f64>,
+    flag_window: usize,
+    #[serde(skip_serializing)]
+    flag_samples_out: Option<String>,
+    #[serde(skip_serializing)]
+    flag_results: Option<String>,
+    #[serde(skip_serializing)]
+    flag_strict: bool,
+    flag_in_flight: Option<usize>,
+    flag_ring: bool,
+    flag_samples: usize,
+    flag_sample_ms: u64,
+    flag_trace: Option<usize>,
+    #[serde(skip_serializing)]
+    flag_trace_out: Option<String>,
+    #[serde(skip_serializing)]
+    flag_trace_out_iters: usize,
+    flag_rates: Option<String>,
+    flag_arrivals: Arrivals,
+    flag_payload: usize,
+    flag_idle: usize,
+    flag_work_ns: u64,
+    flag_working_set: usize,
+    flag_stack_size: usize,
+    flag_paint_stacks: bool,
+    #[serde(skip_serializing)]
     flag_command: Option<String>,
+    #[serde(skip_serializing)]
     flag_quiet: bool,
 }
 
@@ -50,58 +203,232 @@ fn pipe() -> Result<Pipe, std::io::Error> {
     Ok(Pipe { read, write })
 }
 
+/// What each hop does with each message.
+#[derive(Clone, Copy)]
+struct HopParams {
+    payload: usize,
+    work: Work,
+    stack_size: usize,
+    paint_stack: bool,
+}
+
+impl HopParams {
+    fn new(args: &Args) -> HopParams {
+        HopParams {
+            payload: args.flag_payload,
+            work: Work { spin_ns: args.flag_work_ns, working_set: args.flag_working_set },
+            stack_size: args.flag_stack_size,
+            paint_stack: args.flag_paint_stacks,
+        }
+    }
+}
+
+/// A hop's thread, which returns its stack high-water mark if it painted its
+/// stack.
+type Hop = JoinHandle<Option<usize>>;
+
+/// Start a thread that passes messages from upstream to downstream until
+/// either pipe is closed, as directed by `params`. If `laps` is given, count
+/// each message received in it. If `trace` is given, record each message's
+/// passage in it. If `params` says to paint the thread's stack, the thread
+/// returns its stack high-water mark.
+fn spawn_hop(mut upstream_read: UnixStream, mut downstream_write: UnixStream,
+             laps: Option<Arc<AtomicUsize>>, trace: Option<SharedTrace>, params: HopParams)
+             -> Result<Hop, std::io::Error> {
+    let HopParams { payload, work, stack_size, paint_stack } = params;
+    std::thread::Builder::new()
+        .stack_size(stack_size)
+        .spawn(move || {
+        let stack = if paint_stack { utils::paint_stack().ok() } else { None };
+        let mut buf = vec![0_u8; payload];
+        let mut event = HopEvent::default();
+
+        loop {
+            if upstream_read.read_exact(&mut buf).is_err() {
+                break;
+            }
+            if trace.is_some() {
+                event.received = utils::now_ns();
+                event.cpu = utils::current_cpu();
+            }
+            if let Some(laps) = &laps {
+                laps.fetch_add(1, Ordering::Relaxed);
+            }
+            if !work.is_empty() {
+                work.perform_on_stack();
+            }
+            if trace.is_some() {
+                event.sending = utils::now_ns();
+            }
+            if downstream_write.write_all(&buf).is_err() {
+                break;
+            }
+            if let Some(trace) = &trace {
+                event.sent = utils::now_ns();
+                trace.lock().unwrap().record(event);
+            }
+        }
+
+        stack.map(|stack| stack.high_water())
+    })
+}
+
+/// Idle threads, each blocked reading its own pipe until the writing end is
+/// closed.
+struct Idle {
+    writes: Vec<UnixStream>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Start `count` idle threads.
+fn spawn_idle(count: usize, stack_size: usize) -> Result<Idle, std::io::Error> {
+    let mut idle = Idle { writes: Vec::with_capacity(count), threads: Vec::with_capacity(count) };
+    for _i in 0..count {
+        let Pipe { mut read, write } = pipe()?;
+        idle.threads.push(std::thread::Builder::new()
+            .stack_size(stack_size)
+            .spawn(move || drop(read.read(&mut [0_u8; 1])))?);
+        idle.writes.push(write);
+    }
+    Ok(idle)
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
     if !args.flag_quiet {
-        eprintln!("{} tasks, {} iterations:", args.flag_threads, args.flag_iters);
+        eprintln!("environment: {}", utils::Environment::capture());
     }
+    utils::preflight(args.flag_strict)?;
 
-    let Pipe { read: mut upstream_read, write: mut first_write} = pipe()?;
-    for _i in 0..args.flag_threads {
-        let next_pipe = pipe()?;
-        let mut downstream_write = next_pipe.write;
-        std::thread::Builder::new()
-            .stack_size(1024 * 1024)
-            .spawn(move || -> Result<(), std::io::Error> {
-            let mut buf = [0_u8; 1];
+    if args.flag_in_flight == Some(0) {
+        Err("--in-flight must be at least 1")?;
+    }
 
-            loop {
-                upstream_read.read_exact(&mut buf)?;
-                downstream_write.write_all(&buf)?;
-            }
-        })?;
-        upstream_read = next_pipe.read;
+    if args.flag_ring && args.flag_threads == 0 {
+        Err("a ring needs at least one thread")?;
     }
 
-    let mut buf = [0_u8; 1];
+    if args.flag_payload == 0 {
+        Err("--payload must be at least 1")?;
+    }
 
-    // Warm up.
-    for _i in 0..args.flag_warmups {
-        first_write.write_all(b"*")?;
-        upstream_read.read(&mut buf)?;
+    if args.flag_sample_ms == 0 {
+        Err("--sample-ms must be at least 1")?;
     }
 
-    let mut stats = Stats::new();
-    for _i in 0..args.flag_iters {
-        let start = Instant::now();
-        first_write.write_all(b"*")?;
-        upstream_read.read(&mut buf)?;
-        let end = Instant::now();
+    let rates = match &args.flag_rates {
+        Some(list) => Some(parse_rates(list)?),
+        None => None,
+    };
+    if rates.is_some() && (args.flag_ring || args.flag_in_flight.is_some()) {
+        Err("--rates can't be combined with --ring or --in-flight")?;
+    }
+    match args.flag_trace {
+        Some(0) => Err("--trace must be at least 1")?,
+        Some(_) if args.flag_ring || args.flag_in_flight.is_some() || rates.is_some() => {
+            Err("--trace only works when measuring latency")?
+        }
+        _ => (),
+    }
+    if args.flag_trace_out.is_some() && args.flag_trace.is_none() {
+        Err("--trace-out requires --trace")?;
+    }
+    if args.flag_samples_out.is_some()
+        && (args.flag_ring || args.flag_in_flight.is_some() || rates.is_some())
+    {
+        Err("--samples-out only works when measuring latency")?;
+    }
+    if args.flag_adaptive {
+        if args.flag_ring || args.flag_in_flight.is_some() || rates.is_some() {
+            Err("--adaptive only works when measuring latency")?;
+        }
+        if args.flag_window < 2 || args.flag_target <= 0.0 {
+            Err("--window must be at least 2, and --target must be positive")?;
+        }
+        if !matches!(args.flag_quantile, None | Some(0.0..=1.0)) {
+            Err("--quantile must be between 0 and 1")?;
+        }
+    }
 
-        stats.push(UsefulDuration::from(end - start).into());
+    // Leave at least half of each thread's stack for everything else.
+    if args.flag_working_set > args.flag_stack_size / 2 {
+        Err(format!("--working-set may not exceed half of --stack-size ({} bytes), \
+                     to fit on a thread's stack",
+                    args.flag_stack_size / 2))?;
     }
 
     if !args.flag_quiet {
-        eprintln!("mean {} per iteration, stddev {} ({} per task per iter)",
-                  UsefulDuration::from(stats.mean()),
-                  UsefulDuration::from(stats.population_stddev()),
-                  UsefulDuration::from(stats.mean() / args.flag_threads as f64));
+        let mut layout = if args.flag_idle > 0 {
+            format!(" (+{} idle)", args.flag_idle)
+        } else {
+            String::new()
+        };
+        if args.flag_payload != 1 {
+            layout += &format!(", {} byte messages", args.flag_payload);
+        }
+        let work = HopParams::new(&args).work;
+        if !work.is_empty() {
+            layout += &format!(", {}ns work and {} byte working set per hop",
+                              work.spin_ns, work.working_set);
+        }
+        if args.flag_ring {
+            eprintln!("{} tasks{} in a ring, {} in flight, {} samples of {}ms:",
+                      args.flag_threads, layout, args.flag_in_flight.unwrap_or(1),
+                      ring_samples(&args), args.flag_sample_ms);
+        } else if args.flag_adaptive {
+            eprintln!("{} tasks{}, adaptive iterations:", args.flag_threads, layout);
+        } else if rates.is_some() {
+            eprintln!("{} tasks{}, {:?} arrivals, {} per rate:",
+                      args.flag_threads, layout, args.flag_arrivals, iters(&args));
+        } else {
+            match args.flag_in_flight {
+                Some(k) => eprintln!("{} tasks{}, {} in flight, {}:",
+                                     args.flag_threads, layout, k, iters(&args)),
+                None => eprintln!("{} tasks{}, {}:",
+                                  args.flag_threads, layout, iters(&args)),
+            }
+        }
     }
 
-    if let Some(command) = args.flag_command {
+    // Every hop and idle thread has a pipe to read from.
+    let threads = args.flag_threads + args.flag_idle;
+    utils::Needs { threads, fds: 2 * (threads + 1), ..Default::default() }.check()?;
+
+    let idle = spawn_idle(args.flag_idle, args.flag_stack_size)?;
+
+    // `head` is a second handle on the writing end of the first pipe, which
+    // keeps the chain open until we shut it down.
+    let (hops, head) = if args.flag_ring {
+        ring(&args)?
+    } else {
+        let Pipe { read: mut upstream_read, write: first_write} = pipe()?;
+        let mut traces = vec![];
+        let mut hops = Vec::with_capacity(args.flag_threads);
+        for _i in 0..args.flag_threads {
+            let next_pipe = pipe()?;
+            let trace = args.flag_trace.map(TraceRing::shared);
+            traces.extend(trace.clone());
+            hops.push(spawn_hop(upstream_read, next_pipe.write, None, trace, HopParams::new(&args))?);
+            upstream_read = next_pipe.read;
+        }
+
+        // Each of these takes ownership of the chain's ends, and closes them
+        // when it's done.
+        let head = first_write.try_clone()?;
+        if let Some(rates) = &rates {
+            open_loop(&args, rates, first_write, upstream_read)?;
+        } else if let Some(in_flight) = args.flag_in_flight {
+            throughput(&args, in_flight, first_write, upstream_read)?;
+        } else {
+            latency(&args, first_write, upstream_read, &traces)?;
+        }
+        (hops, head)
+    };
+
+    if let Some(command) = &args.flag_command {
         let command = command.replace("{pid}", &std::process::id().to_string());
         let status = Command::new("sh")
             .arg("-c")
@@ -112,5 +439,299 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    tear_down(&args, head, hops, idle)?;
+    Ok(())
+}
+
+/// Shut down `head`, the writing end of the first pipe, and close the idle
+/// threads' pipes, and wait for every thread to exit. If we painted the hops'
+/// stacks, report how much of them they used.
+fn tear_down(args: &Args, head: UnixStream, hops: Vec<Hop>, idle: Idle)
+             -> Result<(), std::io::Error>
+{
+    let Idle { writes, threads } = idle;
+    let tasks = hops.len() + threads.len();
+    let close = || {
+        head.shutdown(Shutdown::Write)?;
+        drop(writes);
+        Ok(())
+    };
+    let join = || {
+        let marks: Vec<usize> = hops.into_iter()
+            .filter_map(|hop| hop.join().ok().flatten())
+            .collect();
+        for thread in threads {
+            let _ = thread.join();
+        }
+        marks
+    };
+    let marks = utils::tear_down(tasks, args.flag_quiet, close, join)?;
+
+    if !args.flag_quiet && args.flag_paint_stacks {
+        eprintln!("{}, of {} bytes each",
+                  utils::describe_high_water(&marks), args.flag_stack_size);
+    }
+    Ok(())
+}
+
+/// Drop bytes into `first_write`, time how long they take to come out of
+/// `upstream_read`, and report the results.
+fn latency(args: &Args, mut first_write: UnixStream, mut upstream_read: UnixStream,
+           traces: &[SharedTrace])
+           -> Result<(), std::io::Error>
+{
+    let message = vec![b'*'; args.flag_payload];
+    let mut buf = vec![0_u8; args.flag_payload];
+    let mut trace = args.flag_trace.map(TraceRing::with_capacity);
+
+    // Send one message through the chain, recording it if we're tracing.
+    let mut round_trip = || -> Result<(), std::io::Error> {
+        match &mut trace {
+            Some(trace) => {
+                let cpu = utils::current_cpu();
+                let sending = utils::now_ns();
+                first_write.write_all(&message)?;
+                let sent = utils::now_ns();
+                upstream_read.read_exact(&mut buf)?;
+                trace.record(HopEvent { received: utils::now_ns(), sending, sent, cpu });
+            }
+            None => {
+                first_write.write_all(&message)?;
+                upstream_read.read_exact(&mut buf)?;
+            }
+        }
+        Ok(())
+    };
+
+    let mut samples = Samples::new();
+    if args.flag_adaptive {
+        let mut sampler = adaptive(args).start();
+        while sampler.wants_more() {
+            let start = Instant::now();
+            round_trip()?;
+            sampler.push((Instant::now() - start).as_secs_f64());
+        }
+
+        let outcome = sampler.finish();
+        if !args.flag_quiet {
+            eprintln!("{}", outcome);
+        }
+        samples = outcome.samples;
+    } else {
+        // Warm up.
+        for _i in warmups(args).start() {
+            round_trip()?;
+        }
+
+        let mut countdown = iters(args).start();
+        for _i in &mut countdown {
+            let start = Instant::now();
+            round_trip()?;
+            let end = Instant::now();
+
+            samples.push(UsefulDuration::from(end - start).into());
+        }
+        if !args.flag_quiet && args.flag_duration.is_some() {
+            eprintln!("{}", countdown);
+        }
+    }
+
+    // If adaptive warmup never settled, we have no measurements to report.
+    let stats = samples.stats();
+    if !args.flag_quiet && stats.mean().is_finite() {
+        let per_hop = stats.mean() / args.flag_threads as f64;
+        eprintln!("mean {} per iteration, stddev {} ({} per task per iter, {} per hop)",
+                  UsefulDuration::from(stats.mean()),
+                  UsefulDuration::from(stats.population_stddev()),
+                  UsefulDuration::from(per_hop),
+                  Bandwidth::new(args.flag_payload as f64, per_hop));
+    }
+
+    if let Some(path) = &args.flag_samples_out {
+        samples.write(std::io::BufWriter::new(std::fs::File::create(path)?))?;
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("thread-brigade", args);
+        record.latencies(&mut samples);
+        record.append_to(path)?;
+    }
+
+    if let Some(trace) = &trace {
+        let tasks: Vec<_> = traces.iter().map(|t| t.lock().unwrap()).collect();
+        if !args.flag_quiet {
+            eprint!("{}", TraceReport::new(trace, &tasks));
+        }
+        if let Some(path) = &args.flag_trace_out {
+            let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            utils::write_chrome_trace(file, trace, &tasks, args.flag_trace_out_iters)?;
+        }
+    }
+
     Ok(())
 }
+
+fn adaptive(args: &Args) -> Adaptive {
+    Adaptive {
+        window: args.flag_window,
+        stability: 0.05,
+        target: args.flag_target,
+        quantile: args.flag_quantile,
+        budget: args.flag_duration.map_or(Duration::from_secs(10), Duration::from),
+    }
+}
+
+fn iters(args: &Args) -> Budget {
+    Budget::new(args.flag_iters, args.flag_duration)
+}
+
+fn warmups(args: &Args) -> Budget {
+    Budget::new(args.flag_warmups, args.flag_warmup_duration)
+}
+
+/// Keep `in_flight` bytes circulating from `first_write` to `upstream_read`,
+/// measure how many come out per second, and report the results.
+fn throughput(args: &Args, in_flight: usize,
+              mut first_write: UnixStream, mut upstream_read: UnixStream)
+              -> Result<(), std::io::Error>
+{
+    let message = vec![b'*'; args.flag_payload];
+    let mut buf = vec![0_u8; args.flag_payload];
+
+    for _i in 0..in_flight {
+        first_write.write_all(&message)?;
+    }
+
+    // Warm up.
+    for _i in warmups(args).start() {
+        upstream_read.read_exact(&mut buf)?;
+        first_write.write_all(&message)?;
+    }
+
+    let start = Instant::now();
+    let mut countdown = iters(args).start();
+    for _i in &mut countdown {
+        upstream_read.read_exact(&mut buf)?;
+        first_write.write_all(&message)?;
+    }
+    let end = Instant::now();
+
+    // Collect the bytes still in flight.
+    for _i in 0..in_flight {
+        upstream_read.read_exact(&mut buf)?;
+    }
+
+    let throughput = Throughput {
+        messages: countdown.completed(),
+        elapsed: (end - start).as_secs_f64(),
+        tasks: args.flag_threads,
+        payload: args.flag_payload,
+    };
+    if !args.flag_quiet {
+        if args.flag_duration.is_some() {
+            eprintln!("{}", countdown);
+        }
+        eprintln!("{}", throughput);
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("thread-brigade", args);
+        record.higher_is_better("messages/s", throughput.messages_per_sec());
+        record.append_to(path)?;
+    }
+
+    Ok(())
+}
+
+/// Drive the chain open-loop at each of `rates`, and report the latency
+/// distribution at each.
+fn open_loop(args: &Args, rates: &[f64],
+             mut first_write: UnixStream, mut upstream_read: UnixStream)
+             -> Result<(), std::io::Error>
+{
+    let mut quantiles = Vec::with_capacity(rates.len());
+    for &rate in rates {
+        let plan = OpenLoop {
+            rate,
+            arrivals: args.flag_arrivals,
+            warmups: warmups(args).iterations_at(rate),
+            tokens: iters(args).iterations_at(rate),
+            payload: args.flag_payload,
+            seed: 1,
+        };
+        let mut results = plan.run(&mut first_write, &mut upstream_read)?;
+        if !args.flag_quiet {
+            eprintln!("{}", results.describe(rate));
+        }
+        quantiles.push((rate, results.corrected.quantile(0.5), results.corrected.quantile(0.99)));
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("thread-brigade", args);
+        for (rate, p50, p99) in quantiles {
+            record.lower_is_better(&format!("p50 at {}/s", rate), p50);
+            record.lower_is_better(&format!("p99 at {}/s", rate), p99);
+        }
+        record.append_to(path)?;
+    }
+
+    Ok(())
+}
+
+/// Build a ring of threads, start bytes circulating, sample the lap rate, and
+/// report the results. Return the threads' handles, and a handle on the
+/// writing end of the first thread's pipe.
+fn ring(args: &Args) -> Result<(Vec<Hop>, UnixStream), std::io::Error> {
+    let laps = Arc::new(AtomicUsize::new(0));
+    let params = HopParams::new(args);
+
+    // Keep a handle on the pipe into the first thread, so we can start
+    // messages circulating once the last thread has taken over its writing end.
+    let Pipe { read: mut upstream_read, write: last_write } = pipe()?;
+    let mut primer = last_write.try_clone()?;
+
+    // The first thread counts laps.
+    let num_tasks = args.flag_threads;
+    let mut hops = Vec::with_capacity(num_tasks);
+    for i in 0..num_tasks - 1 {
+        let next_pipe = pipe()?;
+        let laps = (i == 0).then(|| laps.clone());
+        hops.push(spawn_hop(upstream_read, next_pipe.write, laps, None, params)?);
+        upstream_read = next_pipe.read;
+    }
+    let last_laps = (num_tasks == 1).then(|| laps.clone());
+    hops.push(spawn_hop(upstream_read, last_write, last_laps, None, params)?);
+
+    let message = vec![b'*'; args.flag_payload];
+    for _i in 0..args.flag_in_flight.unwrap_or(1) {
+        primer.write_all(&message)?;
+    }
+
+    let interval = Duration::from_millis(args.flag_sample_ms);
+    let warmup = args.flag_warmup_duration.map_or(interval, Duration::from);
+    let rate = RingRate {
+        passes: utils::sample_rate(&laps, warmup, interval, ring_samples(args)),
+        tasks: args.flag_threads,
+        in_flight: args.flag_in_flight.unwrap_or(1),
+        payload: args.flag_payload,
+    };
+
+    if !args.flag_quiet {
+        eprintln!("{}", rate);
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("thread-brigade", args);
+        record.higher_is_better("laps/s", rate.laps_per_sec());
+        record.append_to(path)?;
+    }
+
+    Ok((hops, primer))
+}
+
+/// The number of lap rate samples to take in ring mode: `--samples`, or enough
+/// to fill `--duration`.
+fn ring_samples(args: &Args) -> usize {
+    Budget::new(args.flag_samples, args.flag_duration)
+        .iterations_at(1000.0 / args.flag_sample_ms as f64)
+}
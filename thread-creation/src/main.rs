@@ -1,41 +1,75 @@
 use docopt::Docopt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::thread;
 use std::time::Instant;
-use utils::{Stats, UsefulDuration};
+use utils::{Budget, Record, Stats, UsefulDuration};
 
-const USAGE: &'static str = "
+const USAGE: &str = "
 Microbenchmark of task creation overhead.
 
 Spawn a given number of asynchronous tasks. Measure how long it takes for the
 spawning process to spawn all the tasks, and how long it takes a spawned task to
 begin execution.
 
+Given `--duration TIME`, like `30s` or `500ms`, the benchmark iterates for that
+long instead of performing `--iters` iterations, and reports how many it
+completed; likewise, `--warmup-duration TIME` replaces `--warmups`.
+
+Each thread gets a `--stack-size` byte stack, or Rust's default if that's not
+given. If `--paint-stacks` is given, each thread fills its stack with a
+pattern when it starts, and the program reports how many bytes of stack the
+threads actually touched.
+
+If `--results FILE` is given, the program appends a JSON record of its
+parameters, the machine it ran on, and its headline statistics to FILE, for
+`compare check` to examine.
+
 Usage:
-  task-creation [--tasks N] [--iters N] [--warmups N]
+  task-creation [options]
 
 Options:
   --tasks <N>       Number of tasks. [default: 1000]
   --iters <N>       Number of iterations to perform. [default: 100]
   --warmups <N>     Number of warmup iterations to perform before benchmarking.
                     [default: 10]
+  --duration <TIME>
+                    Iterate for TIME, like `30s`, instead of `--iters` times.
+  --warmup-duration <TIME>
+                    Warm up for TIME instead of `--warmups` iterations.
+  --results <FILE>  Append a record of this run's statistics to FILE.
+  --strict          Refuse to run if the environment will make results
+                    noisy.
+  --stack-size <BYTES>
+                    Size of each thread's stack.
+  --paint-stacks    Report how much of its stack each thread used.
 ";
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Args {
     flag_tasks: usize,
     flag_iters: usize,
     flag_warmups: usize,
+    flag_duration: Option<UsefulDuration>,
+    flag_warmup_duration: Option<UsefulDuration>,
+    #[serde(skip_serializing)]
+    flag_results: Option<String>,
+    #[serde(skip_serializing)]
+    flag_strict: bool,
+    flag_stack_size: Option<usize>,
+    flag_paint_stacks: bool,
 }
 
-fn main() {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    eprintln!("environment: {}", utils::Environment::capture());
+    utils::preflight(args.flag_strict)?;
+
     struct StartedTask {
         start_time: Instant,
-        handle: thread::JoinHandle<Instant>,
+        handle: thread::JoinHandle<(Instant, Option<usize>)>,
     }
 
     struct FinishedTask {
@@ -43,25 +77,45 @@ fn main() {
         end_time: Instant,
     }
 
+    // Start a thread, which returns the time it started running and, if
+    // we're painting stacks, its stack's high-water mark.
+    let spawn = || -> std::io::Result<StartedTask> {
+        let mut builder = thread::Builder::new();
+        if let Some(size) = args.flag_stack_size {
+            builder = builder.stack_size(size);
+        }
+        let paint = args.flag_paint_stacks;
+        let start_time = Instant::now();
+        let handle = builder.spawn(move || {
+            let end_time = Instant::now();
+            let stack = if paint { utils::paint_stack().ok() } else { None };
+            (end_time, stack.map(|stack| stack.high_water()))
+        })?;
+        Ok(StartedTask { start_time, handle })
+    };
+
     let mut started = Vec::with_capacity(args.flag_tasks);
     let mut finished = Vec::with_capacity(args.flag_tasks);
 
-    eprintln!("{} tasks, {} warmups, {} iterations:", args.flag_tasks, args.flag_warmups, args.flag_iters);
+    let iters = Budget::new(args.flag_iters, args.flag_duration);
+    let warmups = Budget::new(args.flag_warmups, args.flag_warmup_duration);
+    eprintln!("{} tasks, {}, {}:", args.flag_tasks, warmups.describe("warmups"), iters);
+
+    // No thread is joined until they've all been spawned.
+    utils::Needs { threads: args.flag_tasks, ..Default::default() }.check()?;
 
     // Do a few warmup passes.
-    for _warmup in 0..args.flag_warmups {
+    for _warmup in warmups.start() {
         started.clear();
         finished.clear();
 
         for _ in 0..args.flag_tasks {
-            let start_time = Instant::now();
-            let handle = thread::spawn(move || { Instant::now() });
-            started.push(StartedTask { start_time, handle });
+            started.push(spawn()?);
         }
 
         finished.extend(started.drain(..)
                         .map(|StartedTask { start_time, handle }| {
-                            let end_time = handle.join().unwrap();
+                            let (end_time, _) = handle.join().unwrap();
                             FinishedTask { start_time, end_time }
                         }));
     }
@@ -69,22 +123,23 @@ fn main() {
     // Do the real passes.
     let mut creation_times = Stats::new();
     let mut started_times = Stats::new();
-    for _rep in 0..args.flag_iters {
+    let mut stack_marks = vec![];
+    let mut countdown = iters.start();
+    for _rep in &mut countdown {
         started.clear();
         finished.clear();
 
         let start_creation = Instant::now();
         for _ in 0..args.flag_tasks {
-            let start_time = Instant::now();
-            let handle = thread::spawn(move || { Instant::now() });
-            started.push(StartedTask { start_time, handle });
+            started.push(spawn()?);
         }
         let end_creation = Instant::now();
         creation_times.push(UsefulDuration::from(end_creation - start_creation).into());
 
         finished.extend(started.drain(..)
                         .map(|StartedTask { start_time, handle }| {
-                            let end_time = handle.join().unwrap();
+                            let (end_time, mark) = handle.join().unwrap();
+                            stack_marks.extend(mark);
                             FinishedTask { start_time, end_time }
                         }));
 
@@ -93,7 +148,11 @@ fn main() {
                                  UsefulDuration::from(*end_time - *start_time).into()
                              }));
     }
+    countdown.check_completed()?;
 
+    if iters.is_time() {
+        eprintln!("{}", countdown);
+    }
     eprintln!("create a task: mean {} per iter, stddev {} ({} per task)",
               UsefulDuration::from(creation_times.mean()),
               UsefulDuration::from(creation_times.population_stddev()),
@@ -101,4 +160,16 @@ fn main() {
     eprintln!("creation to body: mean {}, stddev {}",
               UsefulDuration::from(started_times.mean()),
               UsefulDuration::from(started_times.population_stddev()));
+    if args.flag_paint_stacks {
+        eprintln!("{}", utils::describe_high_water(&stack_marks));
+    }
+
+    if let Some(path) = &args.flag_results {
+        let mut record = Record::new("thread-creation", &args);
+        record.lower_is_better("creation per task", creation_times.mean() / args.flag_tasks as f64);
+        record.lower_is_better("creation to body", started_times.mean());
+        record.append_to(path)?;
+    }
+
+    Ok(())
 }
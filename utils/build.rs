@@ -0,0 +1,35 @@
+// Record the versions of the compiler and of Tokio this workspace is built
+// with, for `Environment` to report.
+
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map_or("unknown".to_string(), |version| version.trim().to_string());
+    println!("cargo:rustc-env=UTILS_RUSTC_VERSION={}", rustc_version);
+
+    // The benchmarks share the workspace's lock file, so the version of Tokio
+    // it names is the one they use.
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let lock_file = Path::new(&manifest_dir).join("../Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock_file.display());
+    let lock = std::fs::read_to_string(&lock_file).unwrap_or_default();
+    let mut lines = lock.lines();
+    let mut tokio_version = "unknown";
+    while let Some(line) = lines.next() {
+        if line == "name = \"tokio\"" {
+            if let Some(version) = lines.next().and_then(|line| line.strip_prefix("version = ")) {
+                tokio_version = version.trim_matches('"');
+            }
+            break;
+        }
+    }
+    println!("cargo:rustc-env=UTILS_TOKIO_VERSION={}", tokio_version);
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,201 @@
+use crate::{Samples, Stats};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Settings for choosing the number of warmup and measured iterations
+/// automatically, instead of guessing.
+///
+/// Warmup continues until the medians of two consecutive windows of samples
+/// agree to within `stability`. Measurement then continues until the
+/// half-width of the 95% confidence interval of the statistic of interest (the
+/// mean, or the `quantile`'th quantile) is at most `target` times the
+/// statistic, or until `budget` runs out, counting from the start of warmup.
+#[derive(Clone, Copy, Debug)]
+pub struct Adaptive {
+    pub window: usize,
+    pub stability: f64,
+    pub target: f64,
+    pub quantile: Option<f64>,
+    pub budget: Duration,
+}
+
+/// z-score for a two-sided 95% confidence interval.
+const Z_95: f64 = 1.96;
+
+impl Adaptive {
+    pub fn start(self) -> AdaptiveSampler {
+        AdaptiveSampler {
+            settings: self,
+            start: Instant::now(),
+            window: Vec::with_capacity(self.window),
+            previous_median: None,
+            warmups: 0,
+            warmed_up: false,
+            samples: Samples::new(),
+            stats: Stats::new(),
+            next_check: self.window,
+            relative_error: f64::INFINITY,
+            converged: false,
+            out_of_time: false,
+        }
+    }
+}
+
+/// An adaptive measurement in progress. The caller should take a sample and
+/// `push` it for as long as `wants_more` returns true, and then call `finish`.
+pub struct AdaptiveSampler {
+    settings: Adaptive,
+    start: Instant,
+    window: Vec<f64>,
+    previous_median: Option<f64>,
+    warmups: usize,
+    warmed_up: bool,
+    samples: Samples,
+    stats: Stats,
+    next_check: usize,
+    relative_error: f64,
+    converged: bool,
+    out_of_time: bool,
+}
+
+impl AdaptiveSampler {
+    pub fn wants_more(&self) -> bool {
+        !self.converged && !self.out_of_time
+    }
+
+    pub fn push(&mut self, x: f64) {
+        if self.start.elapsed() > self.settings.budget {
+            self.out_of_time = true;
+        }
+
+        if !self.warmed_up {
+            self.warm_up(x);
+            return;
+        }
+
+        self.samples.push(x);
+        self.stats.push(x);
+        if self.samples.len() >= self.next_check {
+            self.relative_error = self.relative_error();
+            self.converged = self.relative_error <= self.settings.target;
+
+            // Checking a quantile means sorting, so back off geometrically.
+            self.next_check = (self.samples.len() + self.samples.len() / 10).max(self.samples.len() + 1);
+        }
+    }
+
+    fn warm_up(&mut self, x: f64) {
+        self.warmups += 1;
+        self.window.push(x);
+        if self.window.len() < self.settings.window {
+            return;
+        }
+
+        self.window.sort_unstable_by(f64::total_cmp);
+        let median = self.window[self.window.len() / 2];
+        self.window.clear();
+        if let Some(previous) = self.previous_median.replace(median) {
+            if (median - previous).abs() <= self.settings.stability * previous {
+                self.warmed_up = true;
+            }
+        }
+    }
+
+    /// Return the half-width of the 95% confidence interval of the statistic
+    /// we're interested in, relative to the statistic itself.
+    fn relative_error(&mut self) -> f64 {
+        let n = self.samples.len() as f64;
+        match self.settings.quantile {
+            None => {
+                Z_95 * self.stats.population_stddev() / n.sqrt() / self.stats.mean()
+            }
+            Some(q) => {
+                // The rank of the q'th quantile in a sample of size n is
+                // binomially distributed; use the normal approximation to find
+                // the ranks bounding the interval.
+                let spread = Z_95 * (n * q * (1.0 - q)).sqrt();
+                let low = self.samples.quantile(((n * q - spread) / n).max(0.0));
+                let high = self.samples.quantile(((n * q + spread) / n).min(1.0));
+                (high - low) / 2.0 / self.samples.quantile(q)
+            }
+        }
+    }
+
+    pub fn finish(self) -> AdaptiveOutcome {
+        AdaptiveOutcome {
+            settings: self.settings,
+            warmups: self.warmups,
+            warmed_up: self.warmed_up,
+            samples: self.samples,
+            converged: self.converged,
+            relative_error: self.relative_error,
+            elapsed: self.start.elapsed(),
+        }
+    }
+}
+
+/// The results of an adaptive measurement.
+pub struct AdaptiveOutcome {
+    settings: Adaptive,
+
+    /// The number of samples discarded as warmup.
+    pub warmups: usize,
+
+    /// True if the samples reached a steady state before the budget ran out.
+    pub warmed_up: bool,
+
+    /// The samples taken after warmup.
+    pub samples: Samples,
+
+    /// True if the statistic's confidence interval met the target.
+    pub converged: bool,
+
+    /// The half-width of the statistic's 95% confidence interval at the last
+    /// check, relative to the statistic.
+    pub relative_error: f64,
+
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for AdaptiveOutcome {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let statistic = match self.settings.quantile {
+            None => "mean".to_string(),
+            Some(q) => format!("p{}", (q * 1000.0).round() / 10.0),
+        };
+        if self.converged {
+            write!(fmt, "converged: ")?;
+        } else if !self.warmed_up {
+            write!(fmt, "did not converge: never reached a steady state in {:.1}s, ",
+                   self.elapsed.as_secs_f64())?;
+        } else {
+            write!(fmt, "did not converge: ran out of time after {:.1}s, ", self.elapsed.as_secs_f64())?;
+        }
+        write!(fmt, "{} warmups, {} iterations", self.warmups, self.samples.len())?;
+        if self.relative_error.is_finite() {
+            write!(fmt, ", 95% CI of the {} within ±{:.2}% (target {:.2}%)",
+                   statistic, self.relative_error * 100.0, self.settings.target * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn converges_on_constant() {
+    let mut sampler = Adaptive {
+        window: 10,
+        stability: 0.05,
+        target: 0.01,
+        quantile: None,
+        budget: Duration::from_secs(60),
+    }.start();
+    let mut pushed = 0;
+    while sampler.wants_more() {
+        sampler.push(1.0 + (pushed % 2) as f64 * 0.01);
+        pushed += 1;
+    }
+    let outcome = sampler.finish();
+    assert!(outcome.warmed_up && outcome.converged);
+    assert_eq!(outcome.warmups, 20);
+    assert_eq!(outcome.samples.len(), 10);
+}
@@ -0,0 +1,29 @@
+use std::io;
+
+/// Return the CPUs this process is allowed to run on, in increasing order.
+///
+/// This respects `taskset` and cgroup cpusets, so it may be a strict subset of
+/// the machine's CPUs.
+pub fn available_cpus() -> io::Result<Vec<usize>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+           .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+           .collect())
+    }
+}
+
+/// Restrict the calling thread to run only on `cpu`.
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+//! Counting heap allocations.
+//!
+//! When the `count-allocations` feature is enabled, this module installs a
+//! global allocator that passes every request along to the real allocator
+//! (see `allocator::Selected`), but first adds it to the calling thread's
+//! running totals. Comparing a thread's totals from before and after it spawns
+//! a task gives the heap cost of spawning it, without the noise of sampling the
+//! resident set size, and without counting what other threads are doing.
+//!
+//! Blocks are counted as freed by whichever thread frees them, so memory a
+//! task frees once it's running elsewhere shows up on that thread's totals.
+
+use std::cell::Cell;
+
+/// Running totals of the heap allocations a thread has made.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Allocations {
+    /// The number of calls to allocate or reallocate a block.
+    pub count: u64,
+
+    /// The total size of the blocks allocated, in bytes. Reallocating counts
+    /// as allocating a block of the new size, and freeing the old one.
+    pub allocated: u64,
+
+    /// The total size of the blocks freed, in bytes.
+    pub freed: u64,
+}
+
+thread_local! {
+    // These must not need destructors or lazy initialization, since the
+    // allocator itself updates them.
+    static TOTALS: Cell<Allocations> = const {
+        Cell::new(Allocations { count: 0, allocated: 0, freed: 0 })
+    };
+}
+
+impl Allocations {
+    /// Return the calling thread's totals so far, or `None` if this program
+    /// wasn't built with the `count-allocations` feature.
+    pub fn now() -> Option<Allocations> {
+        if !cfg!(feature = "count-allocations") {
+            return None;
+        }
+        TOTALS.try_with(Cell::get).ok()
+    }
+
+    /// Call `f`, and return its result along with the allocations the calling
+    /// thread made while it ran, if we're counting them.
+    pub fn during<T>(f: impl FnOnce() -> T) -> (T, Option<Allocations>) {
+        let before = Allocations::now();
+        let result = f();
+        let allocations = before.zip(Allocations::now()).map(|(before, after)| after.since(&before));
+        (result, allocations)
+    }
+
+    /// Return the allocations made between `earlier` and `self`.
+    pub fn since(&self, earlier: &Allocations) -> Allocations {
+        Allocations {
+            count: self.count - earlier.count,
+            allocated: self.allocated - earlier.allocated,
+            freed: self.freed - earlier.freed,
+        }
+    }
+
+    /// Return the growth in the total size of live blocks.
+    pub fn live(&self) -> i64 {
+        self.allocated as i64 - self.freed as i64
+    }
+
+    /// Describe these allocations as the cost of `tasks` tasks.
+    pub fn per_task(&self, tasks: usize) -> String {
+        let tasks = tasks.max(1) as f64;
+        format!("{:.1} allocations, {:.0} bytes allocated, {:.0} bytes still live per task",
+                self.count as f64 / tasks,
+                self.allocated as f64 / tasks,
+                self.live() as f64 / tasks)
+    }
+}
+
+impl std::ops::Add for Allocations {
+    type Output = Allocations;
+    fn add(self, other: Allocations) -> Allocations {
+        Allocations {
+            count: self.count + other.count,
+            allocated: self.allocated + other.allocated,
+            freed: self.freed + other.freed,
+        }
+    }
+}
+
+#[cfg(feature = "count-allocations")]
+mod counting {
+    use super::{Allocations, TOTALS};
+    use crate::allocator::Selected;
+    use std::alloc::{GlobalAlloc, Layout};
+
+    struct Counting;
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// Add to the calling thread's totals. Allocations made while the thread
+    /// is exiting, after its totals are gone, go uncounted.
+    fn note(count: u64, allocated: usize, freed: usize) {
+        let _ = TOTALS.try_with(|totals| {
+            let Allocations { count: c, allocated: a, freed: f } = totals.get();
+            totals.set(Allocations {
+                count: c + count,
+                allocated: a + allocated as u64,
+                freed: f + freed as u64,
+            });
+        });
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            note(1, layout.size(), 0);
+            Selected.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            note(1, layout.size(), 0);
+            Selected.alloc_zeroed(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            note(0, 0, layout.size());
+            Selected.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            note(1, new_size, layout.size());
+            Selected.realloc(ptr, layout, new_size)
+        }
+    }
+}
+
+#[cfg(feature = "count-allocations")]
+#[test]
+fn counting() {
+    let (block, during) = Allocations::during(|| std::hint::black_box(vec![0_u8; 1000]));
+    let during = during.unwrap();
+    drop(block);
+    assert!(during.count >= 1);
+    assert!(during.allocated >= 1000);
+
+    // Other threads' allocations don't count.
+    let (_, elsewhere) = Allocations::during(|| {
+        std::thread::scope(|scope| {
+            scope.spawn(|| drop(std::hint::black_box(vec![0_u8; 1 << 20])));
+        })
+    });
+    assert!(elsewhere.unwrap().allocated < 1 << 20);
+}
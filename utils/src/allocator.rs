@@ -0,0 +1,31 @@
+//! Choosing the global allocator.
+//!
+//! Per-task memory use and creation time depend heavily on the allocator,
+//! especially for threads, since glibc's malloc gives each thread its own
+//! arena. The `jemalloc` and `mimalloc` features replace the system allocator
+//! with those, to show how much of a task's cost is really the allocator's.
+//! If both are enabled, jemalloc wins, and `preflight` says so.
+
+#[cfg(feature = "jemalloc")]
+pub(crate) use tikv_jemallocator::Jemalloc as Selected;
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+pub(crate) use mimalloc::MiMalloc as Selected;
+#[cfg(all(feature = "count-allocations", not(any(feature = "jemalloc", feature = "mimalloc"))))]
+pub(crate) use std::alloc::System as Selected;
+
+/// The name of the allocator this program was built to use: `"system"`,
+/// `"jemalloc"`, or `"mimalloc"`.
+pub const ALLOCATOR: &str = if cfg!(feature = "jemalloc") {
+    "jemalloc"
+} else if cfg!(feature = "mimalloc") {
+    "mimalloc"
+} else {
+    "system"
+};
+
+// When counting allocations, the counting allocator passes requests along to
+// `Selected` instead.
+#[cfg(all(any(feature = "jemalloc", feature = "mimalloc"),
+          not(feature = "count-allocations")))]
+#[global_allocator]
+static GLOBAL: Selected = Selected;
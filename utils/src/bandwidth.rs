@@ -0,0 +1,28 @@
+use std::fmt;
+
+/// A data rate, in bytes per second, that displays itself in sensible units.
+#[derive(Copy, Clone, Debug)]
+pub struct Bandwidth(f64);
+
+impl Bandwidth {
+    /// The rate at which `bytes` bytes are moved in `secs` seconds.
+    pub fn new(bytes: f64, secs: f64) -> Self {
+        Bandwidth(bytes / secs)
+    }
+}
+
+impl fmt::Display for Bandwidth {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        const K: f64 = 1024.0;
+        let b = self.0;
+        if b < 1.5 * K {
+            write!(fmt, "{:.3}B/s", b)
+        } else if b < 1.5 * K * K {
+            write!(fmt, "{:.3}KiB/s", b / K)
+        } else if b < 1.5 * K * K * K {
+            write!(fmt, "{:.3}MiB/s", b / (K * K))
+        } else {
+            write!(fmt, "{:.3}GiB/s", b / (K * K * K))
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::UsefulDuration;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How long a benchmark loop should run: a fixed number of iterations, or for
+/// a fixed amount of time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Budget {
+    Iterations(usize),
+    Time(Duration),
+}
+
+impl Budget {
+    /// Run for `duration` if given, and otherwise for `iterations`. This is
+    /// meant for pairing an `--iters`-style option with a `--duration`-style
+    /// option that overrides it.
+    pub fn new(iterations: usize, duration: Option<UsefulDuration>) -> Budget {
+        match duration {
+            Some(duration) => Budget::Time(duration.into()),
+            None => Budget::Iterations(iterations),
+        }
+    }
+
+    /// Begin running a loop under this budget.
+    ///
+    /// The returned `Countdown` is an iterator that yields iteration numbers
+    /// until the budget is spent. The clock starts now.
+    pub fn start(self) -> Countdown {
+        Countdown { budget: self, start: Instant::now(), end: None, completed: 0 }
+    }
+
+    /// The number of iterations this budget allows if they happen at `rate`
+    /// per second, as when an open-loop driver or a sampler sets the pace.
+    pub fn iterations_at(&self, rate: f64) -> usize {
+        match *self {
+            Budget::Iterations(n) => n,
+            Budget::Time(d) => (d.as_secs_f64() * rate).ceil() as usize,
+        }
+    }
+
+    /// Describe this budget as a quantity of `noun`: "100 warmups", or
+    /// "2.000s of warmups".
+    pub fn describe(&self, noun: &str) -> String {
+        match *self {
+            Budget::Iterations(n) => format!("{} {}", n, noun),
+            Budget::Time(d) => format!("{} of {}", UsefulDuration::from(d), noun),
+        }
+    }
+
+    /// Return true if this budget is a fixed amount of time.
+    pub fn is_time(&self) -> bool {
+        matches!(self, Budget::Time(_))
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.describe("iterations"))
+    }
+}
+
+/// An iterator over the iterations a `Budget` allows.
+///
+/// Its `Display` impl reports the number of iterations completed and how long
+/// they took.
+///
+/// A time budget is checked only between iterations, so the last iteration
+/// may end a little after the deadline; it still counts.
+#[derive(Debug)]
+pub struct Countdown {
+    budget: Budget,
+    start: Instant,
+
+    /// When the budget ran out, once it has.
+    end: Option<Instant>,
+
+    completed: usize,
+}
+
+impl Countdown {
+    /// The number of iterations this countdown has yielded. Once the loop has
+    /// finished, this is the number of iterations it completed.
+    pub fn completed(&self) -> usize {
+        self.completed
+    }
+
+    /// The time from the countdown's start until the budget ran out, or until
+    /// now if it hasn't yet.
+    pub fn elapsed(&self) -> Duration {
+        self.end.unwrap_or_else(Instant::now) - self.start
+    }
+
+    /// Return an error if the loop completed no iterations, leaving nothing
+    /// to report.
+    pub fn check_completed(&self) -> Result<(), String> {
+        if self.completed == 0 {
+            return Err(format!("no iterations completed in a budget of {}", self.budget));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Countdown {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "completed {} iterations in {}",
+               self.completed, UsefulDuration::from(self.elapsed()))
+    }
+}
+
+impl Iterator for Countdown {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let more = match self.budget {
+            Budget::Iterations(n) => self.completed < n,
+            Budget::Time(d) => self.start.elapsed() < d,
+        };
+        if !more {
+            self.end.get_or_insert_with(Instant::now);
+            return None;
+        }
+        self.completed += 1;
+        Some(self.completed - 1)
+    }
+}
+
+#[test]
+fn countdown() {
+    let mut countdown = Budget::Iterations(3).start();
+    assert_eq!((&mut countdown).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert_eq!(countdown.completed(), 3);
+
+    let mut countdown = Budget::Time(Duration::from_millis(20)).start();
+    for _i in &mut countdown {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert!(countdown.completed() > 0);
+    assert!(countdown.elapsed() >= Duration::from_millis(20));
+    assert!(countdown.check_completed().is_ok());
+
+    let mut countdown = Budget::Time(Duration::ZERO).start();
+    assert_eq!((&mut countdown).count(), 0);
+    assert!(countdown.check_completed().is_err());
+}
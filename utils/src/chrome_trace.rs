@@ -0,0 +1,87 @@
+use crate::{complete_iterations, TraceRing};
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::ops::Deref;
+
+/// Write the last `iterations` complete iterations traced in `driver` and
+/// `tasks` to `out`, in the Chrome Trace Event format, which both
+/// `chrome://tracing` and Perfetto can load.
+///
+/// Each task gets a track of its own, showing when it was blocked in `read`,
+/// running, and writing, with the CPU it was on in each span's arguments.
+/// Flow arrows connect each write to the read it woke up, so the wave of
+/// wakeups can be followed down the chain.
+pub fn write_chrome_trace<W, T>(mut out: W, driver: &TraceRing, tasks: &[T], iterations: usize)
+                                -> io::Result<()>
+    where W: Write, T: Deref<Target = TraceRing>
+{
+    let complete = complete_iterations(driver, tasks);
+    let first = complete.end.saturating_sub(iterations).max(complete.start);
+    let origin = match driver.get(first) {
+        Some(event) => event.sending,
+        None => 0,
+    };
+    let us = |ns: u64| ns.saturating_sub(origin) as f64 / 1e3;
+
+    let mut events = vec![];
+    let span = |events: &mut Vec<Value>, name: &str, tid: usize, start: u64, end: u64, cpu: u32| {
+        events.push(json!({
+            "name": name, "ph": "X", "pid": 1, "tid": tid,
+            "ts": us(start), "dur": end.saturating_sub(start) as f64 / 1e3,
+            "args": { "cpu": cpu },
+        }));
+    };
+
+    // Track 0 is the driver; track `i + 1` is task `i`.
+    let tracks = std::iter::once(driver).chain(tasks.iter().map(|t| &**t));
+    for (tid, trace) in tracks.enumerate() {
+        let name = if tid == 0 { "driver".to_string() } else { format!("task {}", tid - 1) };
+        events.push(json!({
+            "name": "thread_name", "ph": "M", "pid": 1, "tid": tid, "args": { "name": name },
+        }));
+        events.push(json!({
+            "name": "thread_sort_index", "ph": "M", "pid": 1, "tid": tid,
+            "args": { "sort_index": tid },
+        }));
+
+        for n in first..complete.end {
+            let event = trace.get(n).unwrap();
+            if tid == 0 {
+                span(&mut events, "writing", tid, event.sending, event.sent, event.cpu);
+                span(&mut events, "blocked in read", tid, event.sent, event.received, event.cpu);
+            } else {
+                if n > first {
+                    let previous = trace.get(n - 1).unwrap();
+                    span(&mut events, "blocked in read", tid, previous.sent, event.received,
+                         event.cpu);
+                }
+                span(&mut events, "running", tid, event.received, event.sending, event.cpu);
+                span(&mut events, "writing", tid, event.sending, event.sent, event.cpu);
+            }
+        }
+    }
+
+    // Draw an arrow from each write to the read it completed.
+    let mut flow_id = 0;
+    for n in first..complete.end {
+        let start = driver.get(n).unwrap();
+        let mut upstream = (0, start);
+        let hops = tasks.iter().enumerate().map(|(i, t)| (i + 1, t.get(n).unwrap()));
+        for (tid, event) in hops.chain(Some((0, start))) {
+            let (upstream_tid, upstream_event) = upstream;
+            events.push(json!({
+                "name": "wakeup", "cat": "wakeup", "ph": "s", "id": flow_id, "pid": 1,
+                "tid": upstream_tid, "ts": us(upstream_event.sending),
+            }));
+            events.push(json!({
+                "name": "wakeup", "cat": "wakeup", "ph": "f", "bp": "e", "id": flow_id, "pid": 1,
+                "tid": tid, "ts": us(event.received),
+            }));
+            flow_id += 1;
+            upstream = (tid, event);
+        }
+    }
+
+    serde_json::to_writer(&mut out, &json!({ "traceEvents": events, "displayTimeUnit": "ns" }))?;
+    out.flush()
+}
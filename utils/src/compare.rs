@@ -0,0 +1,312 @@
+use crate::{Rng, Samples, UsefulDuration};
+use std::fmt;
+
+/// A statistical comparison of two sets of time measurements, in seconds: a
+/// baseline `a`, and a candidate `b`.
+///
+/// The difference and ratio of the means get bootstrap confidence intervals.
+/// Welch's t-test asks whether the means differ; the Mann-Whitney U test asks
+/// whether one distribution tends to produce larger values than the other,
+/// without assuming anything about their shape, which suits latency
+/// distributions with long tails.
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub a: Summary,
+    pub b: Summary,
+
+    /// The mean of `b` minus the mean of `a`.
+    pub difference: Estimate,
+
+    /// The mean of `b` divided by the mean of `a`.
+    pub ratio: Estimate,
+
+    /// The two-sided p-value of Welch's t-test.
+    pub welch_p: f64,
+
+    /// The two-sided p-value of the Mann-Whitney U test, using the normal
+    /// approximation with a correction for ties.
+    pub mann_whitney_p: f64,
+}
+
+/// The basic statistics of one side of a `Comparison`.
+#[derive(Clone, Copy, Debug)]
+pub struct Summary {
+    pub count: usize,
+    pub mean: f64,
+    pub median: f64,
+}
+
+/// A point estimate with a 95% confidence interval.
+#[derive(Clone, Copy, Debug)]
+pub struct Estimate {
+    pub value: f64,
+    pub low: f64,
+    pub high: f64,
+}
+
+impl Estimate {
+    /// Return true if the confidence interval doesn't include `x`.
+    pub fn excludes(&self, x: f64) -> bool {
+        x < self.low || self.high < x
+    }
+}
+
+/// The significance level `Comparison` uses for its verdict.
+const ALPHA: f64 = 0.05;
+
+impl Comparison {
+    /// Compare `a` and `b`, drawing `resamples` bootstrap resamples of each to
+    /// estimate the confidence intervals. The resampling is seeded, so the same
+    /// inputs always produce the same report. Panics if either set has fewer
+    /// than two samples.
+    pub fn new(a: &[f64], b: &[f64], resamples: usize) -> Comparison {
+        assert!(a.len() >= 2 && b.len() >= 2, "comparison needs at least two samples on each side");
+        let mean_a = mean(a);
+        let mean_b = mean(b);
+
+        let mut rng = Rng::new(1);
+        let mut differences = Samples::with_capacity(resamples);
+        let mut ratios = Samples::with_capacity(resamples);
+        for _i in 0..resamples {
+            let resampled_a = resample_mean(a, &mut rng);
+            let resampled_b = resample_mean(b, &mut rng);
+            differences.push(resampled_b - resampled_a);
+            ratios.push(resampled_b / resampled_a);
+        }
+        let interval = |value, samples: &mut Samples| {
+            if samples.is_empty() {
+                return Estimate { value, low: value, high: value };
+            }
+            Estimate { value, low: samples.quantile(0.025), high: samples.quantile(0.975) }
+        };
+
+        Comparison {
+            a: Summary::new(a),
+            b: Summary::new(b),
+            difference: interval(mean_b - mean_a, &mut differences),
+            ratio: interval(mean_b / mean_a, &mut ratios),
+            welch_p: welch_p(a, b),
+            mann_whitney_p: mann_whitney_p(a, b),
+        }
+    }
+
+    /// Return true if `b` differs from `a` by more than noise: the confidence
+    /// interval of the difference excludes zero, and the Mann-Whitney test
+    /// agrees at the 5% level.
+    pub fn significant(&self) -> bool {
+        self.difference.excludes(0.0) && self.mann_whitney_p < ALPHA
+    }
+}
+
+impl Summary {
+    fn new(values: &[f64]) -> Summary {
+        let mut samples: Samples = values.iter().copied().collect();
+        Summary { count: values.len(), mean: mean(values), median: samples.quantile(0.5) }
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// The unbiased sample variance of `values`.
+fn variance(values: &[f64]) -> f64 {
+    let mean = mean(values);
+    values.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (values.len() - 1) as f64
+}
+
+/// Return the mean of a resample of `values`, drawn with replacement.
+fn resample_mean(values: &[f64], rng: &mut Rng) -> f64 {
+    let sum: f64 = (0..values.len()).map(|_| values[rng.below(values.len())]).sum();
+    sum / values.len() as f64
+}
+
+fn welch_p(a: &[f64], b: &[f64]) -> f64 {
+    let (na, nb) = (a.len() as f64, b.len() as f64);
+    let (va, vb) = (variance(a) / na, variance(b) / nb);
+    if va + vb == 0.0 {
+        return if mean(a) == mean(b) { 1.0 } else { 0.0 };
+    }
+    let t = (mean(b) - mean(a)) / (va + vb).sqrt();
+    let df = (va + vb).powi(2) / (va * va / (na - 1.0) + vb * vb / (nb - 1.0));
+    incomplete_beta(df / 2.0, 0.5, df / (df + t * t))
+}
+
+fn mann_whitney_p(a: &[f64], b: &[f64]) -> f64 {
+    // Rank the pooled samples, giving tied values the mean of their ranks.
+    let mut pooled: Vec<(f64, bool)> = a.iter().map(|&x| (x, true))
+        .chain(b.iter().map(|&x| (x, false)))
+        .collect();
+    pooled.sort_unstable_by(|p, q| p.0.total_cmp(&q.0));
+
+    let n = pooled.len() as f64;
+    let mut rank_sum_a = 0.0;
+    let mut tie_correction = 0.0;
+    let mut start = 0;
+    while start < pooled.len() {
+        let end = start + pooled[start..].iter().take_while(|p| p.0 == pooled[start].0).count();
+        let rank = (start + end + 1) as f64 / 2.0;
+        rank_sum_a += rank * pooled[start..end].iter().filter(|p| p.1).count() as f64;
+        let ties = (end - start) as f64;
+        tie_correction += ties * ties * ties - ties;
+        start = end;
+    }
+
+    let (na, nb) = (a.len() as f64, b.len() as f64);
+    let u = rank_sum_a - na * (na + 1.0) / 2.0;
+    let mu = na * nb / 2.0;
+    let sigma = (na * nb / 12.0 * ((n + 1.0) - tie_correction / (n * (n - 1.0)))).sqrt();
+    if sigma == 0.0 {
+        return 1.0;
+    }
+    let z = ((u - mu).abs() - 0.5).max(0.0) / sigma;
+    erfc(z / std::f64::consts::SQRT_2)
+}
+
+/// The natural logarithm of the gamma function, by the Lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146, -86.50532032941677, 24.01409824083091,
+        -1.231739572450155, 0.1208650973866179e-2, -0.5395239384953e-5,
+    ];
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let series = COEFFICIENTS.iter().enumerate()
+        .fold(1.000000000190015, |sum, (i, c)| sum + c / (x + 1.0 + i as f64));
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+/// The regularized incomplete beta function I_x(a, b).
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let front = (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b)
+                 + a * x.ln() + b * (1.0 - x).ln()).exp();
+    // The continued fraction converges quickly only on this side of the mean.
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_fraction(a, b, x) / a
+    } else {
+        1.0 - front * beta_fraction(b, a, 1.0 - x) / b
+    }
+}
+
+/// Evaluate the continued fraction for the incomplete beta function, by the
+/// modified Lentz method.
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let mut c = 1.0;
+    let mut d = 1.0 - (a + b) * x / (a + 1.0);
+    d = 1.0 / if d.abs() < TINY { TINY } else { d };
+    let mut h = d;
+    for m in 1..300 {
+        let m = m as f64;
+        for numerator in [
+            m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m)),
+            -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0)),
+        ] {
+            d = 1.0 + numerator * d;
+            d = 1.0 / if d.abs() < TINY { TINY } else { d };
+            c = 1.0 + numerator / c;
+            if c.abs() < TINY {
+                c = TINY;
+            }
+            h *= d * c;
+        }
+        if (d * c - 1.0).abs() < 1e-12 {
+            break;
+        }
+    }
+    h
+}
+
+/// The complementary error function, with a fractional error below 1.2e-7.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let poly = [
+        -1.26551223, 1.00002368, 0.37409196, 0.09678418, -0.18628806,
+        0.27886807, -1.13520398, 1.48851587, -0.82215223, 0.17087277,
+    ].iter().rev().fold(0.0, |sum, c| sum * t + c);
+    let result = t * (-z * z + poly).exp();
+    if x >= 0.0 { result } else { 2.0 - result }
+}
+
+/// Format a signed time difference.
+struct SignedDuration(f64);
+
+impl fmt::Display for SignedDuration {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0.0 { '-' } else { '+' };
+        write!(fmt, "{}{}", sign, UsefulDuration::from(self.0.abs()))
+    }
+}
+
+/// Format a p-value, without claiming more precision than the arithmetic has.
+struct PValue(f64);
+
+impl fmt::Display for PValue {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 < 1e-15 {
+            write!(fmt, "p < 1e-15")
+        } else if self.0 < 0.001 {
+            write!(fmt, "p = {:.1e}", self.0)
+        } else {
+            write!(fmt, "p = {:.3}", self.0)
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for (label, side) in [("a", &self.a), ("b", &self.b)] {
+            writeln!(fmt, "{}: {} samples, mean {}, median {}",
+                     label, side.count,
+                     UsefulDuration::from(side.mean),
+                     UsefulDuration::from(side.median))?;
+        }
+        writeln!(fmt, "difference (b - a): {}, 95% CI [{}, {}]",
+                 SignedDuration(self.difference.value),
+                 SignedDuration(self.difference.low),
+                 SignedDuration(self.difference.high))?;
+        writeln!(fmt, "ratio (b / a): {:.3}, 95% CI [{:.3}, {:.3}]",
+                 self.ratio.value, self.ratio.low, self.ratio.high)?;
+        writeln!(fmt, "Welch's t-test: {}", PValue(self.welch_p))?;
+        writeln!(fmt, "Mann-Whitney U test: {}", PValue(self.mann_whitney_p))?;
+        if self.significant() {
+            let direction = if self.difference.value > 0.0 { "slower" } else { "faster" };
+            writeln!(fmt, "b is significantly {} than a", direction)
+        } else {
+            writeln!(fmt, "no significant difference between a and b")
+        }
+    }
+}
+
+#[test]
+fn special_functions() {
+    let close = |x: f64, y: f64| (x - y).abs() < 1e-6;
+    assert!(close(ln_gamma(5.0), 24.0_f64.ln()));
+    assert!(close(erfc(0.0), 1.0));
+    assert!(close(erfc(1.0), 0.157299207));
+    // I_x(a, 1) = x^a.
+    assert!(close(incomplete_beta(3.0, 1.0, 0.5), 0.125));
+    // The two-sided p-value of t = 2 with 10 degrees of freedom.
+    assert!(close(incomplete_beta(5.0, 0.5, 10.0 / 14.0), 0.0733880));
+}
+
+#[test]
+fn comparisons() {
+    let a: Vec<f64> = (0..200).map(|i| 1e-6 + (i % 10) as f64 * 1e-8).collect();
+    let same = Comparison::new(&a, &a, 200);
+    assert!(!same.significant());
+    assert!(same.welch_p > 0.99 && same.mann_whitney_p > 0.9);
+
+    let b: Vec<f64> = a.iter().map(|x| x * 1.5).collect();
+    let slower = Comparison::new(&a, &b, 200);
+    assert!(slower.significant());
+    assert!(slower.ratio.low > 1.4 && slower.ratio.high < 1.6);
+    assert!(slower.welch_p < 1e-6 && slower.mann_whitney_p < 1e-6);
+}
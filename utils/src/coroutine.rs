@@ -0,0 +1,349 @@
+//! Stackful coroutines, switched with `ucontext`.
+//!
+//! Each coroutine runs on its own `mmap`ped stack, with an inaccessible guard
+//! page at the low end. Coroutines are scheduled cooperatively on the thread
+//! that spawned them: a coroutine runs until it calls [`park`], or until its
+//! body returns. [`run`] resumes ready coroutines in FIFO order until none
+//! remain.
+//!
+//! Note that glibc's `swapcontext` saves and restores the signal mask, which
+//! costs a `rt_sigprocmask` system call on every switch. So these coroutines
+//! stay out of the kernel's scheduler, but not entirely out of the kernel.
+
+use std::cell::{RefCell, UnsafeCell};
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+
+/// The identifier of a coroutine, unique among those live on its thread.
+pub type CoroutineId = usize;
+
+/// A coroutine stack, allocated with `mmap`.
+struct Stack {
+    base: *mut libc::c_void,
+    len: usize,
+}
+
+impl Stack {
+    fn new(size: usize) -> io::Result<Stack> {
+        let page = page_size();
+        let len = size.div_ceil(page) * page + page;
+        unsafe {
+            let base = libc::mmap(std::ptr::null_mut(), len,
+                                  libc::PROT_READ | libc::PROT_WRITE,
+                                  libc::MAP_PRIVATE | libc::MAP_ANONYMOUS
+                                  | libc::MAP_NORESERVE | libc::MAP_STACK,
+                                  -1, 0);
+            if base == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            let stack = Stack { base, len };
+
+            // Stacks grow down, so put the guard page at the bottom.
+            if libc::mprotect(base, page, libc::PROT_NONE) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(stack)
+        }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base, self.len);
+        }
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+struct Coroutine {
+    context: libc::ucontext_t,
+    body: Option<Box<dyn FnOnce()>>,
+    done: bool,
+    _stack: Stack,
+}
+
+struct Scheduler {
+    /// The context `run` was called from, to which parking coroutines return.
+    context: libc::ucontext_t,
+
+    /// All live coroutines, indexed by id. Boxed so that their contexts don't
+    /// move while `swapcontext` has pointers to them.
+    coroutines: Vec<Option<Box<Coroutine>>>,
+
+    /// Free slots in `coroutines`.
+    free: Vec<CoroutineId>,
+
+    /// Coroutines ready to run, in the order they should run.
+    ready: VecDeque<CoroutineId>,
+
+    /// The coroutine currently running, if any.
+    current: Option<CoroutineId>,
+}
+
+thread_local! {
+    static SCHEDULER: UnsafeCell<Scheduler> = const {
+        UnsafeCell::new(Scheduler {
+            context: unsafe { std::mem::zeroed() },
+            coroutines: vec![],
+            free: vec![],
+            ready: VecDeque::new(),
+            current: None,
+        })
+    };
+}
+
+/// Return a pointer to this thread's scheduler.
+///
+/// The scheduler is only ever touched by its own thread, and no reference to
+/// it is held across a context switch, so borrows through this pointer never
+/// overlap.
+fn scheduler() -> *mut Scheduler {
+    SCHEDULER.with(|s| s.get())
+}
+
+/// Create a new coroutine that will run `body` on a stack of `stack_size`
+/// bytes, and mark it ready to run.
+///
+/// The coroutine doesn't begin execution until some call to [`run`] on this
+/// thread resumes it. It's fine to spawn coroutines from within other
+/// coroutines.
+pub fn spawn<F>(stack_size: usize, body: F) -> io::Result<CoroutineId>
+    where F: FnOnce() + 'static
+{
+    let stack = Stack::new(stack_size)?;
+    let page = page_size();
+    let mut coroutine = Box::new(Coroutine {
+        context: unsafe { std::mem::zeroed() },
+        body: Some(Box::new(body)),
+        done: false,
+        _stack: stack,
+    });
+
+    unsafe {
+        if libc::getcontext(&mut coroutine.context) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        coroutine.context.uc_stack.ss_sp = (coroutine._stack.base as *mut u8).add(page) as *mut _;
+        coroutine.context.uc_stack.ss_size = coroutine._stack.len - page;
+        coroutine.context.uc_link = std::ptr::null_mut();
+        libc::makecontext(&mut coroutine.context, trampoline, 0);
+
+        let sched = &mut *scheduler();
+        let id = match sched.free.pop() {
+            Some(id) => {
+                sched.coroutines[id] = Some(coroutine);
+                id
+            }
+            None => {
+                sched.coroutines.push(Some(coroutine));
+                sched.coroutines.len() - 1
+            }
+        };
+        sched.ready.push_back(id);
+        Ok(id)
+    }
+}
+
+/// The initial function of every coroutine's context.
+extern "C" fn trampoline() {
+    let body = unsafe {
+        let sched = &mut *scheduler();
+        let id = sched.current.unwrap();
+        sched.coroutines[id].as_mut().unwrap().body.take().unwrap()
+    };
+
+    // Unwinding can't cross the bottom of a `makecontext` stack.
+    if std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)).is_err() {
+        eprintln!("coroutine panicked");
+        std::process::abort();
+    }
+
+    unsafe {
+        let sched = scheduler();
+        let context = {
+            let sched = &mut *sched;
+            let coroutine = sched.coroutines[sched.current.unwrap()].as_mut().unwrap();
+            coroutine.done = true;
+            &mut coroutine.context as *mut libc::ucontext_t
+        };
+        libc::swapcontext(context, std::ptr::addr_of!((*sched).context));
+    }
+    unreachable!("finished coroutine resumed");
+}
+
+/// Return the id of the coroutine calling this function.
+///
+/// Panics if called from outside a coroutine.
+pub fn current() -> CoroutineId {
+    unsafe { (*scheduler()).current.expect("not running in a coroutine") }
+}
+
+/// Suspend the calling coroutine until some other coroutine passes its id to
+/// [`wake`].
+///
+/// Panics if called from outside a coroutine.
+pub fn park() {
+    unsafe {
+        let sched = scheduler();
+        let context = {
+            let sched = &mut *sched;
+            let id = sched.current.expect("not running in a coroutine");
+            &mut sched.coroutines[id].as_mut().unwrap().context as *mut libc::ucontext_t
+        };
+        libc::swapcontext(context, std::ptr::addr_of!((*sched).context));
+    }
+}
+
+/// Mark the coroutine `id` as ready to run.
+///
+/// The coroutine must be parked, and not already woken.
+pub fn wake(id: CoroutineId) {
+    unsafe {
+        (&mut *scheduler()).ready.push_back(id);
+    }
+}
+
+/// Run coroutines on this thread until none are ready.
+///
+/// Coroutines still parked when this returns stay alive, and can be resumed by
+/// a later call, once something wakes them.
+pub fn run() {
+    unsafe {
+        let sched = scheduler();
+        assert!((*sched).current.is_none(), "coroutine::run called from a coroutine");
+        loop {
+            let (id, context) = {
+                let sched = &mut *sched;
+                let id = match sched.ready.pop_front() {
+                    Some(id) => id,
+                    None => break,
+                };
+                sched.current = Some(id);
+                (id, &sched.coroutines[id].as_ref().unwrap().context as *const libc::ucontext_t)
+            };
+            libc::swapcontext(std::ptr::addr_of_mut!((*sched).context), context);
+
+            // We're back on our own stack, so it's safe to free a finished
+            // coroutine's.
+            let sched = &mut *sched;
+            sched.current = None;
+            if sched.coroutines[id].as_ref().unwrap().done {
+                sched.coroutines[id] = None;
+                sched.free.push(id);
+            }
+        }
+    }
+}
+
+/// An unbounded single-consumer queue for passing values between coroutines on
+/// the same thread.
+pub struct Channel<T> {
+    inner: Rc<RefCell<ChannelInner<T>>>,
+}
+
+struct ChannelInner<T> {
+    queue: VecDeque<T>,
+    waiter: Option<CoroutineId>,
+    closed: bool,
+}
+
+impl<T> Clone for Channel<T> {
+    fn clone(&self) -> Self {
+        Channel { inner: self.inner.clone() }
+    }
+}
+
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Channel::new()
+    }
+}
+
+impl<T> Channel<T> {
+    pub fn new() -> Channel<T> {
+        Channel {
+            inner: Rc::new(RefCell::new(ChannelInner {
+                queue: VecDeque::new(),
+                waiter: None,
+                closed: false,
+            }))
+        }
+    }
+
+    /// Enqueue `value`, waking the receiving coroutine if it is waiting.
+    pub fn send(&self, value: T) {
+        let mut inner = self.inner.borrow_mut();
+        inner.queue.push_back(value);
+        if let Some(waiter) = inner.waiter.take() {
+            wake(waiter);
+        }
+    }
+
+    /// Close the channel, waking the receiving coroutine if it is waiting.
+    /// Values already sent can still be received.
+    pub fn close(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.closed = true;
+        if let Some(waiter) = inner.waiter.take() {
+            wake(waiter);
+        }
+    }
+
+    /// Dequeue the next value, parking the calling coroutine until one arrives.
+    /// Return `None` once the channel is closed and empty.
+    pub fn recv(&self) -> Option<T> {
+        loop {
+            {
+                let mut inner = self.inner.borrow_mut();
+                if let Some(value) = inner.queue.pop_front() {
+                    return Some(value);
+                }
+                if inner.closed {
+                    return None;
+                }
+                assert!(inner.waiter.is_none(), "two coroutines receiving from one channel");
+                inner.waiter = Some(current());
+            }
+            park();
+        }
+    }
+}
+
+#[test]
+fn channel_chain() {
+    let first = Channel::new();
+    let mut upstream = first.clone();
+    for _ in 0..10 {
+        let downstream = Channel::new();
+        let (read, write) = (upstream, downstream.clone());
+        spawn(16 * 1024, move || {
+            while let Some(n) = read.recv() {
+                write.send(n + 1);
+            }
+            write.close();
+        }).unwrap();
+        upstream = downstream;
+    }
+
+    let result = Rc::new(RefCell::new(vec![]));
+    let (driver_first, driver_last, driver_result) = (first.clone(), upstream.clone(), result.clone());
+    spawn(16 * 1024, move || {
+        for i in 0..3 {
+            driver_first.send(i * 100);
+            driver_result.borrow_mut().push(driver_last.recv().unwrap());
+        }
+    }).unwrap();
+
+    run();
+    assert_eq!(*result.borrow(), vec![10, 110, 210]);
+
+    // Closing the head of the chain lets every coroutine finish.
+    first.close();
+    run();
+    assert_eq!(upstream.recv(), None);
+}
@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// The circumstances a benchmark ran under.
+///
+/// A number is meaningless without knowing what produced it: the same
+/// benchmark can differ by a factor of several between a laptop on battery and
+/// a tuned server, or before and after a kernel update changes the Spectre
+/// mitigations. Every run captures one of these, prints a summary, and stores
+/// the whole thing alongside its results.
+///
+/// Fields this process can't determine, like the frequency governor inside
+/// most virtual machines, are `None` or `"unknown"`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Environment {
+    pub host: String,
+
+    /// The kernel's name, release and machine architecture, as from `uname
+    /// -srm`.
+    pub kernel: String,
+
+    pub cpu_model: String,
+
+    /// The number of CPUs the kernel knows about.
+    pub cpus: usize,
+
+    /// The CPUs this process may run on, as a list of ranges like `0-3,8`.
+    pub affinity: String,
+
+    /// CPU 0's frequency scaling governor, like `performance` or `powersave`.
+    pub governor: Option<String>,
+
+    /// The kernel's assessment of each CPU vulnerability, like `meltdown` or
+    /// `spectre_v2`, from `/sys/devices/system/cpu/vulnerabilities`.
+    pub vulnerabilities: BTreeMap<String, String>,
+
+    pub rustc: String,
+    pub tokio: String,
+
+    /// The global allocator the benchmark was built with, from `ALLOCATOR`.
+    /// Records written before this was captured used the system allocator.
+    #[serde(default = "system_allocator")]
+    pub allocator: String,
+
+    /// This process's control groups, as listed in `/proc/self/cgroup`,
+    /// omitting the root groups.
+    pub cgroup: String,
+
+    /// The hypervisor vendor, or just `"unknown"`, if this is a virtual
+    /// machine.
+    pub virtual_machine: Option<String>,
+
+    /// The container runtime, if this process is running in a container.
+    pub container: Option<String>,
+}
+
+impl Environment {
+    /// Examine the machine and process we're running in.
+    pub fn capture() -> Environment {
+        let (host, kernel) = uname();
+        let cpuinfo = read("/proc/cpuinfo").unwrap_or_default();
+        let cpuinfo_field = |field: &str| {
+            cpuinfo.lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim() == field)
+                .map(|(_, value)| value.trim().to_string())
+        };
+        let cpu_model = cpuinfo_field("model name").unwrap_or_else(|| "unknown CPU".to_string());
+        let cpus = cpuinfo.lines().filter(|line| line.starts_with("processor")).count();
+        let hypervisor = cpuinfo_field("flags")
+            .is_some_and(|flags| flags.split_whitespace().any(|flag| flag == "hypervisor"));
+
+        let affinity = crate::available_cpus().map_or("unknown".to_string(), |cpus| cpu_ranges(&cpus));
+        let governor = read("/sys/devices/system/cpu/cpu0/cpufreq/scaling_governor");
+
+        let mut vulnerabilities = BTreeMap::new();
+        if let Ok(entries) = fs::read_dir("/sys/devices/system/cpu/vulnerabilities") {
+            for entry in entries.flatten() {
+                if let Some(status) = read(entry.path()) {
+                    vulnerabilities.insert(entry.file_name().to_string_lossy().into_owned(), status);
+                }
+            }
+        }
+
+        let cgroup = read("/proc/self/cgroup").unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.splitn(3, ':').nth(2))
+            .filter(|path| *path != "/")
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let virtual_machine = if hypervisor {
+            Some(read("/sys/class/dmi/id/sys_vendor").unwrap_or_else(|| "unknown".to_string()))
+        } else {
+            None
+        };
+
+        let container = if let Ok(runtime) = std::env::var("container") {
+            Some(runtime)
+        } else if Path::new("/.dockerenv").exists() {
+            Some("docker".to_string())
+        } else if Path::new("/run/.containerenv").exists() {
+            Some("podman".to_string())
+        } else {
+            None
+        };
+
+        Environment {
+            host,
+            kernel,
+            cpu_model,
+            cpus,
+            affinity,
+            governor,
+            vulnerabilities,
+            rustc: env!("UTILS_RUSTC_VERSION").to_string(),
+            tokio: env!("UTILS_TOKIO_VERSION").to_string(),
+            allocator: crate::ALLOCATOR.to_string(),
+            cgroup: if cgroup.is_empty() { "/".to_string() } else { cgroup },
+            virtual_machine,
+            container,
+        }
+    }
+
+    /// Return a short description of the machine, to keep results from
+    /// different machines apart: the host name, the CPU model, and the number
+    /// of CPUs this process may use.
+    pub fn fingerprint(&self) -> String {
+        let usable = parse_cpu_ranges(&self.affinity).len();
+        format!("{}: {}, {} CPUs", self.host, self.cpu_model, usable)
+    }
+
+    /// Return the number of vulnerabilities the kernel reports as mitigated,
+    /// and the number it reports as unmitigated.
+    pub fn mitigations(&self) -> (usize, usize) {
+        let count = |prefix| self.vulnerabilities.values()
+            .filter(|status| status.starts_with(prefix))
+            .count();
+        (count("Mitigation"), count("Vulnerable"))
+    }
+
+    /// Describe the ways `self` differs from `earlier` that could affect a
+    /// benchmark's results on the same machine, like a new kernel or compiler.
+    pub fn changes_since(&self, earlier: &Environment) -> Vec<String> {
+        let mut changes = vec![];
+        let mut compare = |what: &str, before: &str, after: &str| {
+            if before != after {
+                changes.push(format!("{} changed from {} to {}", what, before, after));
+            }
+        };
+        compare("kernel", &earlier.kernel, &self.kernel);
+        compare("affinity", &earlier.affinity, &self.affinity);
+        compare("governor",
+                earlier.governor.as_deref().unwrap_or("unknown"),
+                self.governor.as_deref().unwrap_or("unknown"));
+        compare("rustc", &earlier.rustc, &self.rustc);
+        compare("tokio", &earlier.tokio, &self.tokio);
+        compare("allocator", &earlier.allocator, &self.allocator);
+        compare("cgroup", &earlier.cgroup, &self.cgroup);
+        for (name, status) in &self.vulnerabilities {
+            compare(name, earlier.vulnerabilities.get(name).map_or("unknown", String::as_str), status);
+        }
+        changes
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (mitigated, vulnerable) = self.mitigations();
+        write!(f, "{}, {}, {} CPUs, affinity {}, governor {}, \
+                   {} mitigations ({} vulnerable), {}, tokio {}, {} allocator",
+               self.kernel, self.cpu_model, self.cpus, self.affinity,
+               self.governor.as_deref().unwrap_or("unknown"),
+               mitigated, vulnerable, self.rustc, self.tokio, self.allocator)?;
+        if self.cgroup != "/" {
+            write!(f, ", cgroup {}", self.cgroup)?;
+        }
+        match self.virtual_machine.as_deref() {
+            Some("unknown") => write!(f, ", virtual machine")?,
+            Some(vendor) => write!(f, ", virtual machine ({})", vendor)?,
+            None => {}
+        }
+        if let Some(runtime) = &self.container {
+            write!(f, ", {} container", runtime)?;
+        }
+        Ok(())
+    }
+}
+
+fn system_allocator() -> String {
+    "system".to_string()
+}
+
+/// Read a short text file, like those in `/sys`, and return its trimmed
+/// contents, or `None` if it can't be read.
+fn read<P: AsRef<Path>>(path: P) -> Option<String> {
+    fs::read_to_string(path).ok().map(|text| text.trim().to_string())
+}
+
+/// Return this machine's host name, and its kernel's name, release, and
+/// architecture.
+fn uname() -> (String, String) {
+    unsafe {
+        let mut name: libc::utsname = std::mem::zeroed();
+        if libc::uname(&mut name) != 0 {
+            return ("unknown".to_string(), "unknown".to_string());
+        }
+        let field = |chars: &[libc::c_char]| {
+            std::ffi::CStr::from_ptr(chars.as_ptr()).to_string_lossy().into_owned()
+        };
+        (field(&name.nodename),
+         format!("{} {} {}", field(&name.sysname), field(&name.release), field(&name.machine)))
+    }
+}
+
+/// Format a sorted list of CPU numbers as ranges, like `0-3,8`.
+fn cpu_ranges(cpus: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for &cpu in cpus {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == cpu => *end = cpu,
+            _ => ranges.push((cpu, cpu)),
+        }
+    }
+    ranges.iter()
+        .map(|&(start, end)| if start == end {
+            start.to_string()
+        } else {
+            format!("{}-{}", start, end)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse a list of CPU ranges like `0-3,8`, as used in `/sys`, returning the
+/// CPU numbers it includes. Ignore anything unparseable.
+pub(crate) fn parse_cpu_ranges(text: &str) -> Vec<usize> {
+    text.split(',')
+        .filter_map(|range| match range.split_once('-') {
+            Some((start, end)) => Some(start.trim().parse().ok()?..=end.trim().parse().ok()?),
+            None => range.trim().parse().ok().map(|cpu| cpu..=cpu),
+        })
+        .flatten()
+        .collect()
+}
+
+#[test]
+fn ranges() {
+    assert_eq!(cpu_ranges(&[]), "");
+    assert_eq!(cpu_ranges(&[0]), "0");
+    assert_eq!(cpu_ranges(&[0, 1, 2, 3, 8, 10, 11]), "0-3,8,10-11");
+    assert_eq!(parse_cpu_ranges("0-3,8,10-11"), [0, 1, 2, 3, 8, 10, 11]);
+    assert_eq!(parse_cpu_ranges(""), [] as [usize; 0]);
+
+    let mut environment = Environment::capture();
+    environment.host = "host".to_string();
+    environment.cpu_model = "CPU".to_string();
+    environment.affinity = "0-3,8,10-11".to_string();
+    assert_eq!(environment.fingerprint(), "host: CPU, 7 CPUs");
+}
@@ -1,5 +1,49 @@
+mod adaptive;
+mod affinity;
+mod allocations;
+mod allocator;
+mod bandwidth;
+mod budget;
+mod chrome_trace;
+mod compare;
+mod environment;
+mod limits;
+mod open_loop;
+mod preflight;
+mod rate;
+mod results;
+mod rng;
+mod samples;
+mod stack;
 mod stats;
+mod teardown;
+mod topology;
+mod trace;
 mod useful_duration;
+mod work;
 
+pub mod coroutine;
+
+pub use adaptive::*;
+pub use affinity::*;
+pub use allocations::*;
+pub use allocator::*;
+pub use bandwidth::*;
+pub use budget::*;
+pub use chrome_trace::*;
+pub use compare::*;
+pub use environment::*;
+pub use limits::*;
+pub use open_loop::*;
+pub use preflight::*;
+pub use rate::*;
+pub use results::*;
+pub use rng::*;
+pub use samples::*;
+pub use stack::*;
 pub use stats::*;
+pub use teardown::*;
+pub use topology::*;
+pub use trace::*;
 pub use useful_duration::*;
+pub use work::*;
@@ -0,0 +1,379 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The system resources a benchmark run will need, beyond what a small
+/// process uses anyway.
+///
+/// Running tens of thousands of tasks runs into a thicket of operating system
+/// limits, any of which makes thread or pipe creation fail with an
+/// uninformative error partway through. `check` looks for them all up front.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Needs {
+    /// The number of threads the run will create.
+    pub threads: usize,
+
+    /// The number of file descriptors the run will open.
+    pub fds: usize,
+
+    /// The number of memory map areas the run will create, other than the
+    /// ones every thread needs, which `check` counts itself.
+    pub map_areas: usize,
+}
+
+/// A limit that prevents a run, and the command that would lift it.
+struct Blocked {
+    limit: String,
+    fix: String,
+}
+
+/// File descriptors to allow for beyond `Needs::fds`: standard streams, the
+/// async runtime's event queues, output files, and so on.
+const SPARE_FDS: usize = 64;
+
+/// The number of memory map areas each Rust thread uses: its stack, the
+/// stack's guard page, and the same again for the alternate signal stack the
+/// standard library gives it to report stack overflows.
+pub const THREAD_MAP_AREAS: usize = 4;
+
+impl Needs {
+    /// Check whether the system's limits allow a run with these needs.
+    ///
+    /// Raise this process's soft limits on open files and on processes as far
+    /// as its hard limits allow, if that's what it takes. If some limit still
+    /// blocks the run, print which, and the command to raise it, and return an
+    /// error.
+    pub fn check(&self) -> Result<(), String> {
+        let mut blocked = vec![];
+
+        let fds = self.fds + SPARE_FDS;
+        match raise_soft_limit(libc::RLIMIT_NOFILE, fds) {
+            Ok(hard) if hard < fds => blocked.push(Blocked {
+                limit: format!("this run needs {} file descriptors, but the hard limit \
+                                on open files (RLIMIT_NOFILE) is {}", fds, hard),
+                fix: format!("sudo prlimit --nofile={0}:{0} --pid $$", fds),
+            }),
+            Ok(_) => {}
+            Err(e) => eprintln!("warning: couldn't raise the open file limit: {}", e),
+        }
+
+        if self.threads > 0 {
+            // RLIMIT_NPROC counts every thread the user has, in any process.
+            let existing = user_threads();
+            let total = existing + self.threads;
+            match raise_soft_limit(libc::RLIMIT_NPROC, total) {
+                Ok(hard) if hard < total => blocked.push(Blocked {
+                    limit: format!("this run needs {} threads, and your user has {} \
+                                    already, but the hard limit on processes per user \
+                                    (RLIMIT_NPROC) is {}", self.threads, existing, hard),
+                    fix: format!("sudo prlimit --nproc={0}:{0} --pid $$", 2 * total),
+                }),
+                Ok(_) => {}
+                Err(e) => eprintln!("warning: couldn't raise the process limit: {}", e),
+            }
+
+            // Every thread on the system, ours included, takes up a pid.
+            let existing = system_threads().unwrap_or(0);
+            let total = existing + self.threads;
+            for sysctl in ["kernel.pid_max", "kernel.threads-max"] {
+                let value = read_number(format!("/proc/sys/{}", sysctl.replace('.', "/")));
+                if let Some(max) = value.filter(|&max| max < total) {
+                    // On 64-bit machines, pid_max can't exceed 2^22.
+                    let suggestion = if sysctl == "kernel.pid_max" {
+                        (2 * total).min(1 << 22)
+                    } else {
+                        2 * total
+                    };
+                    blocked.push(Blocked {
+                        limit: format!("this run needs {} threads, and {} exist already, \
+                                        but {} is {}", self.threads, existing, sysctl, max),
+                        fix: format!("sudo sysctl {}={}", sysctl, suggestion),
+                    });
+                }
+            }
+
+            blocked.extend(cgroup_pids_limits(self.threads));
+        }
+
+        let map_areas = self.map_areas + THREAD_MAP_AREAS * self.threads;
+        if map_areas > 0 {
+            let existing = fs::read_to_string("/proc/self/maps")
+                .map_or(0, |maps| maps.lines().count());
+            let total = existing + map_areas;
+            let max_map_count = read_number("/proc/sys/vm/max_map_count");
+            if let Some(max) = max_map_count.filter(|&max| max < total) {
+                blocked.push(Blocked {
+                    limit: format!("this run needs {} memory map areas, and {} exist already, \
+                                    but vm.max_map_count is {}", map_areas, existing, max),
+                    fix: format!("sudo sysctl vm.max_map_count={}", 2 * total),
+                });
+            }
+        }
+
+        for Blocked { limit, fix } in &blocked {
+            eprintln!("error: {}", limit);
+            eprintln!("    to raise it: {}", fix);
+        }
+        if !blocked.is_empty() {
+            return Err("system limits are too low for this run; see the README's section \
+                        on large numbers of threads".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Raise the soft limit on `resource` to at least `needed`, if it's lower and
+/// the hard limit allows. Return the hard limit.
+fn raise_soft_limit(resource: libc::__rlimit_resource_t, needed: usize) -> io::Result<usize> {
+    unsafe {
+        let mut limit: libc::rlimit = std::mem::zeroed();
+        if libc::getrlimit(resource, &mut limit) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let hard = if limit.rlim_max == libc::RLIM_INFINITY {
+            usize::MAX
+        } else {
+            limit.rlim_max as usize
+        };
+        if limit.rlim_cur != libc::RLIM_INFINITY && (limit.rlim_cur as usize) < needed {
+            limit.rlim_cur = needed.min(hard) as libc::rlim_t;
+            if libc::setrlimit(resource, &limit) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(hard)
+    }
+}
+
+/// Return the number of threads running on the whole system, from the
+/// fourth field of `/proc/loadavg`, which looks like `2/1234`.
+fn system_threads() -> Option<usize> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    loadavg.split_whitespace().nth(3)?.split_once('/')?.1.parse().ok()
+}
+
+/// Return the number of threads in all the processes whose real user ID is
+/// ours, which is what RLIMIT_NPROC limits. Processes that exit while we look
+/// are skipped.
+fn user_threads() -> usize {
+    let uid = unsafe { libc::getuid() }.to_string();
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut threads = 0;
+    for entry in entries.flatten() {
+        let status = match fs::read_to_string(entry.path().join("status")) {
+            Ok(status) => status,
+            Err(_) => continue,
+        };
+        let field = |name: &str| {
+            status.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        };
+        if field("Uid").and_then(|uids| uids.split_whitespace().next()) == Some(uid.as_str()) {
+            threads += field("Threads").and_then(|n| n.trim().parse().ok()).unwrap_or(0);
+        }
+    }
+    threads
+}
+
+/// Check the `pids` controller's limit in this process's cgroup and every
+/// ancestor, since an ancestor's limit covers all its descendants. Return a
+/// `Blocked` for each group that has no room for `threads` more.
+fn cgroup_pids_limits(threads: usize) -> Vec<Blocked> {
+    pids_limits().into_iter()
+        .filter(|limit| limit.current + threads > limit.max)
+        .map(|PidsLimit { group, max, current }| Blocked {
+            limit: format!("this run needs {} threads, but the cgroup {} allows \
+                            only {} pids, and {} are in use",
+                           threads, group.display(), max, current),
+            fix: format!("sudo sh -c 'echo {} > {}'",
+                         2 * (current + threads), group.join("pids.max").display()),
+        })
+        .collect()
+}
+
+/// A limit the `pids` cgroup controller places on a group of processes.
+#[derive(Clone, Debug)]
+pub struct PidsLimit {
+    /// The cgroup's directory, under `/sys/fs/cgroup`.
+    pub group: PathBuf,
+
+    /// The most threads the group's processes may have, in all.
+    pub max: usize,
+
+    /// The number of threads the group's processes have now.
+    pub current: usize,
+}
+
+/// Return the `pids` limits on this process's cgroup and all its ancestors,
+/// omitting groups with no limit.
+pub fn pids_limits() -> Vec<PidsLimit> {
+    let mut limits = vec![];
+    let cgroups = fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    for line in cgroups.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (hierarchy, controllers, path) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(hierarchy), Some(controllers), Some(path)) => (hierarchy, controllers, path),
+            _ => continue,
+        };
+        // cgroup v2 has a single hierarchy, numbered zero; under v1, the
+        // `pids` controller has its own.
+        let root = if hierarchy == "0" && controllers.is_empty() {
+            PathBuf::from("/sys/fs/cgroup")
+        } else if controllers.split(',').any(|controller| controller == "pids") {
+            PathBuf::from("/sys/fs/cgroup/pids")
+        } else {
+            continue;
+        };
+
+        let mut group = root.join(path.trim_start_matches('/'));
+        while group.starts_with(&root) {
+            if let (Some(max), Some(current)) = (read_number(group.join("pids.max")),
+                                                 read_number(group.join("pids.current"))) {
+                limits.push(PidsLimit { group: group.clone(), max, current });
+            }
+            if !group.pop() {
+                break;
+            }
+        }
+    }
+    limits
+}
+
+/// A snapshot of the resources this process, and the system as a whole, are
+/// using, for explaining why creating another task failed.
+#[derive(Clone, Debug)]
+pub struct Usage {
+    /// This process's open file descriptors.
+    pub fds: usize,
+
+    /// This process's memory map areas, from `/proc/self/maps`.
+    pub map_areas: usize,
+
+    /// This process's threads.
+    pub threads: usize,
+
+    /// Threads on the whole system.
+    pub system_threads: Option<usize>,
+
+    /// The `pids` limit on this process's cgroup, or the ancestor's with the
+    /// least room left, if any.
+    pub pids: Option<PidsLimit>,
+
+    /// The memory available for new allocations without swapping, in bytes.
+    pub mem_available: Option<u64>,
+}
+
+impl Usage {
+    pub fn now() -> Usage {
+        let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+        let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+        let field = |text: &str, name: &str| -> Option<u64> {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?
+                .split_whitespace().next()?
+                .parse().ok()
+        };
+        Usage {
+            fds: fs::read_dir("/proc/self/fd").map_or(0, |entries| entries.count()),
+            map_areas: fs::read_to_string("/proc/self/maps").map_or(0, |maps| maps.lines().count()),
+            threads: field(&status, "Threads").unwrap_or(0) as usize,
+            system_threads: system_threads(),
+            pids: pids_limits().into_iter()
+                .min_by_key(|limit| limit.max.saturating_sub(limit.current)),
+            mem_available: field(&meminfo, "MemAvailable").map(|kib| kib * 1024),
+        }
+    }
+
+    /// Guess which limit made creating a task fail with `error`, given that
+    /// this process and its children had `tasks` tasks at the time.
+    pub fn probable_limit(&self, error: &io::Error, tasks: usize) -> String {
+        // The kernel reports most of these with EAGAIN or ENOMEM, so look at
+        // how close we are to each limit, not just at the error number.
+        const CLOSE: usize = 16;
+        match error.raw_os_error() {
+            Some(libc::EMFILE) => {
+                return "the open file limit, RLIMIT_NOFILE (`ulimit -n`)".to_string();
+            }
+            Some(libc::ENFILE) => return "the system-wide open file limit, fs.file-max".to_string(),
+            _ => {}
+        }
+        if let Some(limit) = &self.pids {
+            if limit.current + CLOSE >= limit.max {
+                return format!("the cgroup `pids.max` limit of {} on {}",
+                               limit.max, limit.group.display());
+            }
+        }
+        if let Ok(soft) = soft_limit(libc::RLIMIT_NPROC) {
+            if tasks + CLOSE >= soft {
+                return format!("the per-user process limit, RLIMIT_NPROC (`ulimit -u`), of {}",
+                               soft);
+            }
+        }
+        if let Some(threads) = self.system_threads {
+            for sysctl in ["kernel.threads-max", "kernel.pid_max"] {
+                let max = read_number(format!("/proc/sys/{}", sysctl.replace('.', "/")));
+                if max.is_some_and(|max| threads + CLOSE >= max) {
+                    return format!("the system-wide limit {} = {}", sysctl, max.unwrap());
+                }
+            }
+        }
+        if let Some(max) = read_number("/proc/sys/vm/max_map_count") {
+            if self.map_areas + CLOSE >= max {
+                return format!("the memory map area limit vm.max_map_count = {}", max);
+            }
+        }
+        let low_memory = self.mem_available.is_some_and(|free| free < 64 << 20);
+        if error.raw_os_error() == Some(libc::ENOMEM) || low_memory {
+            return "memory: either physical memory ran out, or the kernel refused to \
+                    overcommit more (see vm.overcommit_memory)".to_string();
+        }
+        format!("unknown: no limit we know of is nearly exhausted, but the error was {}", error)
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unknown = || "unknown".to_string();
+        writeln!(f, "open file descriptors: {}", self.fds)?;
+        writeln!(f, "memory map areas: {}", self.map_areas)?;
+        writeln!(f, "threads in this process: {}", self.threads)?;
+        writeln!(f, "threads on the system: {}",
+                 self.system_threads.map_or_else(unknown, |n| n.to_string()))?;
+        match &self.pids {
+            Some(limit) => writeln!(f, "cgroup pids: {} of {} in {}",
+                                    limit.current, limit.max, limit.group.display())?,
+            None => writeln!(f, "cgroup pids: no limit")?,
+        }
+        writeln!(f, "memory available: {}",
+                 self.mem_available.map_or_else(unknown, |bytes| format!("{} MiB", bytes >> 20)))
+    }
+}
+
+/// Return the soft limit on `resource`, or `usize::MAX` if there is none.
+fn soft_limit(resource: libc::__rlimit_resource_t) -> io::Result<usize> {
+    unsafe {
+        let mut limit: libc::rlimit = std::mem::zeroed();
+        if libc::getrlimit(resource, &mut limit) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(if limit.rlim_cur == libc::RLIM_INFINITY { usize::MAX } else { limit.rlim_cur as usize })
+    }
+}
+
+/// Read a file containing a single number, like those in `/proc/sys`. Return
+/// `None` if it can't be read, or holds something else, like `max`.
+fn read_number<P: AsRef<Path>>(path: P) -> Option<usize> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[test]
+fn counts_our_own_threads() {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let ours: usize = status.lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .unwrap().trim().parse().unwrap();
+    assert!(user_threads() >= ours);
+}
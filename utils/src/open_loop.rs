@@ -0,0 +1,218 @@
+use crate::{Rng, Samples, UsefulDuration};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// How an open-loop driver spaces out the tokens it injects.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub enum Arrivals {
+    /// Evenly spaced, at exactly the requested rate.
+    Fixed,
+
+    /// Exponentially distributed gaps, with the requested mean rate.
+    Poisson,
+}
+
+/// An open-loop load generator.
+///
+/// A closed-loop driver injects a token only after the previous one comes out,
+/// so a slow token delays the next one's injection, and the queueing the slow
+/// token would have caused is never seen. An open-loop driver instead injects
+/// tokens on a fixed schedule from a timer thread of its own, whether or not
+/// earlier tokens have come out, and measures each token's latency from the
+/// time the schedule said it should be sent. That way, if the injector itself
+/// gets held up, the delay is still charged to the tokens that were due, which
+/// corrects for what Gil Tene calls 'coordinated omission'.
+#[derive(Clone, Copy, Debug)]
+pub struct OpenLoop {
+    /// Tokens per second to offer.
+    pub rate: f64,
+    pub arrivals: Arrivals,
+
+    /// Number of tokens to inject before we start recording.
+    pub warmups: usize,
+
+    /// Number of tokens to record.
+    pub tokens: usize,
+
+    /// Size of each token, in bytes.
+    pub payload: usize,
+
+    /// Seed for the Poisson arrival schedule.
+    pub seed: u64,
+}
+
+/// The results of an `OpenLoop` run. All latencies are in seconds.
+pub struct OpenLoopResults {
+    /// Latencies measured from each token's scheduled send time.
+    pub corrected: Samples,
+
+    /// Latencies measured from the time each token was actually sent, as a
+    /// closed-loop driver would measure them.
+    pub uncorrected: Samples,
+
+    /// The rate at which recorded tokens actually came out, in tokens per
+    /// second.
+    pub achieved_rate: f64,
+}
+
+impl OpenLoop {
+    /// Return each token's scheduled send time, as an offset from the start of
+    /// the run.
+    fn schedule(&self) -> Vec<Duration> {
+        let mut rng = Rng::new(self.seed);
+        let mut t = 0.0;
+        (0..self.warmups + self.tokens)
+            .map(|_| {
+                let offset = Duration::from_secs_f64(t);
+                t += match self.arrivals {
+                    Arrivals::Fixed => 1.0 / self.rate,
+                    Arrivals::Poisson => -(1.0 - rng.next_f64()).ln() / self.rate,
+                };
+                offset
+            })
+            .collect()
+    }
+
+    /// Drive a chain whose entry is `head` and whose exit is `tail`, which
+    /// must deliver tokens in the order they were sent.
+    ///
+    /// The injector runs on a thread of its own; the calling thread collects
+    /// the tokens. If the injector gets an error, it shuts `head` down for
+    /// writing, so that the collector sees end-of-file once the chain has
+    /// passed that along, rather than waiting forever for tokens that will
+    /// never come.
+    pub fn run<R: Read>(&self, head: &mut UnixStream, tail: &mut R) -> io::Result<OpenLoopResults> {
+        if self.tokens == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("open-loop run at {}/s would record no tokens",
+                                              self.rate)));
+        }
+
+        let schedule = self.schedule();
+        let (sent_tx, sent_rx) = mpsc::channel();
+        let start = Instant::now();
+
+        std::thread::scope(|scope| {
+            let injector = scope.spawn(|| -> io::Result<()> {
+                let token = vec![b'*'; self.payload];
+                let mut inject = || -> io::Result<()> {
+                    for offset in &schedule {
+                        let due = start + *offset;
+                        let now = Instant::now();
+                        if due > now {
+                            std::thread::sleep(due - now);
+                        }
+                        let sent = Instant::now();
+                        head.write_all(&token)?;
+                        if sent_tx.send(sent).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(())
+                };
+                let result = inject();
+                if result.is_err() {
+                    let _ = head.shutdown(Shutdown::Write);
+                }
+                result
+            });
+
+            let mut buf = vec![0_u8; self.payload];
+            let mut corrected = Samples::with_capacity(self.tokens);
+            let mut uncorrected = Samples::with_capacity(self.tokens);
+            let mut first_recorded = start;
+            let mut read_error = None;
+            for (i, offset) in schedule.iter().enumerate() {
+                if let Err(error) = tail.read_exact(&mut buf) {
+                    read_error = Some(error);
+                    break;
+                }
+                let received = Instant::now();
+                let sent = match sent_rx.recv() {
+                    Ok(sent) => sent,
+                    Err(_) => break,
+                };
+                if i == self.warmups {
+                    first_recorded = start + *offset;
+                }
+                if i >= self.warmups {
+                    corrected.push((received - (start + *offset)).as_secs_f64());
+                    uncorrected.push((received - sent).as_secs_f64());
+                }
+            }
+            let elapsed = (Instant::now() - first_recorded).as_secs_f64();
+
+            // The injector's error, if any, explains the collector's.
+            injector.join().expect("open-loop injector panicked")?;
+            if let Some(error) = read_error {
+                return Err(error);
+            }
+            Ok(OpenLoopResults {
+                corrected,
+                uncorrected,
+                achieved_rate: self.tokens as f64 / elapsed,
+            })
+        })
+    }
+}
+
+impl OpenLoopResults {
+    /// Describe the latency distribution of a run offered `rate` tokens per
+    /// second.
+    pub fn describe(&mut self, rate: f64) -> String {
+        format!("{:.0}/s offered, {:.0}/s achieved: p50 {}, p90 {}, p99 {}, p99.9 {}, max {} (uncorrected p99 {})",
+                rate, self.achieved_rate,
+                UsefulDuration::from(self.corrected.quantile(0.5)),
+                UsefulDuration::from(self.corrected.quantile(0.9)),
+                UsefulDuration::from(self.corrected.quantile(0.99)),
+                UsefulDuration::from(self.corrected.quantile(0.999)),
+                UsefulDuration::from(self.corrected.max()),
+                UsefulDuration::from(self.uncorrected.quantile(0.99)))
+    }
+}
+
+/// Parse a comma-separated list of open-loop rates, in tokens per second.
+pub fn parse_rates(list: &str) -> Result<Vec<f64>, String> {
+    let rates = list.split(',')
+        .map(|r| r.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("bad --rates list: {}", e))?;
+    if rates.iter().any(|&r| !(r > 0.0 && r.is_finite())) {
+        return Err("--rates must all be positive".to_string());
+    }
+    Ok(rates)
+}
+
+#[test]
+fn open_loop_echo() {
+    // Drive a "chain" of one thread that copies tokens from one socketpair to
+    // another.
+    let (mut head, mut echo_in) = UnixStream::pair().unwrap();
+    let (mut echo_out, mut tail) = UnixStream::pair().unwrap();
+    let echo = std::thread::spawn(move || io::copy(&mut echo_in, &mut echo_out));
+
+    let mut plan = OpenLoop {
+        rate: 10000.0,
+        arrivals: Arrivals::Poisson,
+        warmups: 5,
+        tokens: 100,
+        payload: 4,
+        seed: 1,
+    };
+    let results = plan.run(&mut head, &mut tail).unwrap();
+    assert_eq!(results.corrected.len(), 100);
+    assert_eq!(results.uncorrected.len(), 100);
+    assert!(results.corrected.values().iter().all(|&latency| latency >= 0.0));
+    assert!(results.uncorrected.values().iter().all(|&latency| latency >= 0.0));
+    assert!(results.achieved_rate > 0.0);
+
+    plan.tokens = 0;
+    assert!(plan.run(&mut head, &mut tail).is_err());
+
+    head.shutdown(Shutdown::Write).unwrap();
+    echo.join().unwrap().unwrap();
+}
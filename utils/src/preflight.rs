@@ -0,0 +1,90 @@
+use crate::environment::parse_cpu_ranges;
+use std::fs;
+
+/// Return descriptions of conditions that will make this process's
+/// measurements noisy or misleading.
+///
+/// These are all things that are best fixed before running benchmarks, rather
+/// than averaged away afterwards: other processes competing for the CPUs, CPU
+/// frequencies that change under load, hyperthreads sharing a core's
+/// execution units, and unoptimized code.
+pub fn noise_warnings() -> Vec<String> {
+    let mut warnings = vec![];
+    let cpus = crate::available_cpus().unwrap_or_default();
+    let cpu_file = |cpu: usize, file: &str| {
+        fs::read_to_string(format!("/sys/devices/system/cpu/cpu{}/{}", cpu, file))
+            .ok()
+            .map(|text| text.trim().to_string())
+    };
+
+    if cfg!(debug_assertions) {
+        warnings.push("this is a debug build; build with `cargo build --release`".to_string());
+    }
+
+    // Our own process has barely started, so any load is someone else's.
+    let load = fs::read_to_string("/proc/loadavg").ok()
+        .and_then(|text| text.split_whitespace().next()?.parse::<f64>().ok());
+    if let Some(load) = load {
+        if load > 0.25 * cpus.len().max(1) as f64 {
+            warnings.push(format!("the one-minute load average is {:.2}, with {} CPUs available",
+                                  load, cpus.len()));
+        }
+    }
+
+    let slow_governors: Vec<_> = cpus.iter()
+        .filter_map(|&cpu| Some((cpu, cpu_file(cpu, "cpufreq/scaling_governor")?)))
+        .filter(|(_, governor)| governor != "performance")
+        .collect();
+    if let Some((cpu, governor)) = slow_governors.first() {
+        warnings.push(format!("CPU {} uses the `{}` frequency governor, and {} CPUs in all \
+                               aren't using `performance`",
+                              cpu, governor, slow_governors.len()));
+    }
+
+    let no_turbo = fs::read_to_string("/sys/devices/system/cpu/intel_pstate/no_turbo");
+    let boost = fs::read_to_string("/sys/devices/system/cpu/cpufreq/boost");
+    if no_turbo.is_ok_and(|text| text.trim() == "0")
+        || boost.is_ok_and(|text| text.trim() == "1")
+    {
+        warnings.push("turbo boost is enabled, so CPU frequencies vary with load \
+                       and temperature".to_string());
+    }
+
+    // Report each group of hyperthreads we might run on only once. A sibling
+    // outside our affinity set can't compete with us, so it doesn't count.
+    let mut shared = vec![];
+    for &cpu in &cpus {
+        if let Some(siblings) = cpu_file(cpu, "topology/thread_siblings_list") {
+            let available = parse_cpu_ranges(&siblings).into_iter()
+                .filter(|sibling| cpus.contains(sibling))
+                .count();
+            if available > 1 && !shared.contains(&siblings) {
+                shared.push(siblings);
+            }
+        }
+    }
+    if !shared.is_empty() {
+        warnings.push(format!("these CPUs share cores as SMT siblings: {}", shared.join("; ")));
+    }
+
+    warnings
+}
+
+/// Print a warning for each of `noise_warnings`. If `strict` is true and
+/// there were any, return an error instead of letting the benchmark run.
+pub fn preflight(strict: bool) -> Result<(), String> {
+    if cfg!(all(feature = "jemalloc", feature = "mimalloc")) {
+        eprintln!("warning: the `jemalloc` and `mimalloc` features are both enabled; \
+                   using jemalloc");
+    }
+
+    let warnings = noise_warnings();
+    for warning in &warnings {
+        eprintln!("warning: {}", warning);
+    }
+    if strict && !warnings.is_empty() {
+        return Err(format!("refusing to run with --strict: the environment \
+                            will give noisy results ({} problems)", warnings.len()));
+    }
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use crate::{Bandwidth, Stats, UsefulDuration};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Measure how fast `counter` advances, in counts per second.
+///
+/// Let `warmup` pass to allow things to settle, and then take `samples`
+/// samples, each covering one more `interval`. This blocks the calling thread,
+/// so run it on a thread of its own, away from whatever is advancing the
+/// counter.
+pub fn sample_rate(counter: &AtomicUsize, warmup: Duration, interval: Duration, samples: usize)
+                   -> Stats {
+    std::thread::sleep(warmup);
+
+    let mut stats = Stats::new();
+    let mut last_count = counter.load(Ordering::Relaxed);
+    let mut last_time = Instant::now();
+    for _i in 0..samples {
+        std::thread::sleep(interval);
+        let count = counter.load(Ordering::Relaxed);
+        let time = Instant::now();
+        stats.push((count - last_count) as f64 / (time - last_time).as_secs_f64());
+        last_count = count;
+        last_time = time;
+    }
+
+    stats
+}
+
+/// The rate at which tokens circulate around a ring, as measured by
+/// `sample_rate` counting the tokens passing one of its tasks.
+pub struct RingRate {
+    /// Tokens passing the counting task per second.
+    pub passes: Stats,
+    pub tasks: usize,
+    pub in_flight: usize,
+    pub payload: usize,
+}
+
+impl RingRate {
+    /// Return the mean rate at which each token completes a lap. With K
+    /// tokens in flight, the counting task sees K passes per lap.
+    pub fn laps_per_sec(&self) -> f64 {
+        self.passes.mean() / self.in_flight as f64
+    }
+
+    /// Return the mean number of hops per second, across the whole ring.
+    pub fn hops_per_sec(&self) -> f64 {
+        self.passes.mean() * self.tasks as f64
+    }
+}
+
+impl fmt::Display for RingRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hops_per_sec = self.hops_per_sec();
+        write!(f, "mean {:.0} laps/s, stddev {:.0} ({:.0} hops/s, ",
+               self.laps_per_sec(),
+               self.passes.population_stddev() / self.in_flight as f64,
+               hops_per_sec)?;
+        if hops_per_sec > 0.0 {
+            write!(f, "{} per hop, {})",
+                   UsefulDuration::from(1.0 / hops_per_sec),
+                   Bandwidth::new(hops_per_sec * self.payload as f64, 1.0))
+        } else {
+            write!(f, "n/a per hop)")
+        }
+    }
+}
+
+/// The throughput of a chain kept busy with several messages in flight at
+/// once.
+pub struct Throughput {
+    /// The number of messages that made it through the chain.
+    pub messages: usize,
+
+    /// How long they took, in seconds.
+    pub elapsed: f64,
+
+    pub tasks: usize,
+    pub payload: usize,
+}
+
+impl Throughput {
+    /// Return the number of messages that made it through the chain per
+    /// second.
+    pub fn messages_per_sec(&self) -> f64 {
+        if self.messages == 0 {
+            return 0.0;
+        }
+        self.messages as f64 / self.elapsed
+    }
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hops = self.messages as f64 * self.tasks as f64;
+        if hops == 0.0 {
+            return write!(f, "0 messages/s, 0 hops/s (n/a per hop)");
+        }
+        write!(f, "{:.0} messages/s, {:.0} hops/s ({} per hop, {})",
+               self.messages_per_sec(),
+               hops / self.elapsed,
+               UsefulDuration::from(self.elapsed / hops),
+               Bandwidth::new(hops * self.payload as f64, self.elapsed))
+    }
+}
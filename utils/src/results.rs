@@ -0,0 +1,209 @@
+use crate::{Environment, Samples};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One benchmark run's results, as stored in a results file.
+///
+/// A results file holds one `Record` per line, as JSON, and is only ever
+/// appended to, so it accumulates a history of runs. Records with the same
+/// benchmark, parameters and machine measure the same thing, and can be
+/// compared to spot regressions.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    /// The name of the benchmark program.
+    pub benchmark: String,
+
+    /// The program's command-line options, by name without the leading
+    /// dashes, except those that don't affect the measurements.
+    pub params: Map<String, Value>,
+
+    /// A description of the machine the benchmark ran on, from
+    /// `Environment::fingerprint`.
+    pub machine: String,
+
+    /// Everything else we know about the circumstances of the run. Records
+    /// written before this was captured lack it.
+    #[serde(default)]
+    pub environment: Option<Environment>,
+
+    /// When the run finished, in seconds since the Unix epoch.
+    pub time: u64,
+
+    pub stats: BTreeMap<String, Statistic>,
+}
+
+/// A single statistic from a run, and which direction counts as an
+/// improvement.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Statistic {
+    pub value: f64,
+    pub better: Better,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Better {
+    Lower,
+    Higher,
+}
+
+impl Record {
+    /// Start a record of a run of `benchmark` with the given arguments.
+    ///
+    /// `args` is the program's docopt `Args` struct. Its fields' `flag_`,
+    /// `arg_` and `cmd_` prefixes are dropped to produce the parameter names.
+    /// Mark fields that don't affect the measurements, like output file names,
+    /// with `#[serde(skip_serializing)]`.
+    pub fn new<A: Serialize>(benchmark: &str, args: &A) -> Record {
+        let params = match serde_json::to_value(args) {
+            Ok(Value::Object(fields)) => fields.into_iter()
+                .map(|(name, value)| {
+                    let name = ["flag_", "arg_", "cmd_"].iter()
+                        .find_map(|prefix| name.strip_prefix(prefix))
+                        .map_or(name.clone(), str::to_string);
+                    (name, value)
+                })
+                .collect(),
+            _ => panic!("benchmark arguments should serialize as a JSON object"),
+        };
+        let environment = Environment::capture();
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Record {
+            benchmark: benchmark.to_string(),
+            params,
+            machine: environment.fingerprint(),
+            environment: Some(environment),
+            time,
+            stats: BTreeMap::new(),
+        }
+    }
+
+    /// Record a statistic for which smaller values are better, like a latency.
+    pub fn lower_is_better(&mut self, name: &str, value: f64) {
+        self.insert(name, Statistic { value, better: Better::Lower });
+    }
+
+    /// Record a statistic for which larger values are better, like a rate.
+    pub fn higher_is_better(&mut self, name: &str, value: f64) {
+        self.insert(name, Statistic { value, better: Better::Higher });
+    }
+
+    /// Record `stat` under `name`, unless its value is infinite or NaN: JSON
+    /// can't represent those, and there's nothing to compare them against.
+    fn insert(&mut self, name: &str, stat: Statistic) {
+        if !stat.value.is_finite() {
+            eprintln!("warning: not recording {} statistic {}", stat.value, name);
+            return;
+        }
+        self.stats.insert(name.to_string(), stat);
+    }
+
+    /// Record the mean, median and 99th percentile of `samples`, a set of
+    /// latencies. If `samples` is empty, record nothing.
+    pub fn latencies(&mut self, samples: &mut Samples) {
+        if samples.is_empty() {
+            return;
+        }
+        self.lower_is_better("mean", samples.stats().mean());
+        self.lower_is_better("p50", samples.quantile(0.5));
+        self.lower_is_better("p99", samples.quantile(0.99));
+    }
+
+    /// Return a key identifying what this record measured: records with equal
+    /// keys are comparable.
+    pub fn key(&self) -> (String, String, String) {
+        (self.benchmark.clone(), Value::Object(self.params.clone()).to_string(), self.machine.clone())
+    }
+
+    /// Append this record to the results file at `path`, creating it if need
+    /// be.
+    pub fn append_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        // Write the whole line at once, so that concurrent runs appending to
+        // the same file don't interleave their records.
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?
+            .write_all(line.as_bytes())
+    }
+}
+
+/// Read all the records in the results file at `path`, in the order they
+/// were appended.
+///
+/// A line that doesn't parse as a record doesn't spoil the rest of the file:
+/// it is skipped, and described in the second vector returned.
+pub fn read_records<P: AsRef<Path>>(path: P) -> io::Result<(Vec<Record>, Vec<String>)> {
+    parse_records(BufReader::new(File::open(path)?))
+}
+
+fn parse_records<R: BufRead>(reader: R) -> io::Result<(Vec<Record>, Vec<String>)> {
+    let mut records = vec![];
+    let mut skipped = vec![];
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => skipped.push(format!("line {}: {}", number + 1, e)),
+        }
+    }
+    Ok((records, skipped))
+}
+
+#[test]
+fn record_round_trip() {
+    #[derive(Serialize)]
+    struct Args {
+        flag_threads: usize,
+        arg_file: String,
+    }
+
+    let args = Args { flag_threads: 10, arg_file: "x".to_string() };
+    let mut record = Record::new("test", &args);
+    record.lower_is_better("mean", 1.5e-6);
+    record.higher_is_better("rate", 1000.0);
+    assert_eq!(record.params.keys().collect::<Vec<_>>(), ["file", "threads"]);
+
+    let text = serde_json::to_string(&record).unwrap();
+    let read: Record = serde_json::from_str(&text).unwrap();
+    assert_eq!(read.key(), record.key());
+    assert_eq!(read.stats["mean"].value, 1.5e-6);
+    assert_eq!(read.stats["rate"].better, Better::Higher);
+    assert!(read.environment.is_some());
+
+    // Records from before environments were captured still parse.
+    let old = r#"{"benchmark":"test","params":{},"machine":"m","time":0,"stats":{}}"#;
+    assert!(serde_json::from_str::<Record>(old).unwrap().environment.is_none());
+}
+
+#[test]
+fn bad_records() {
+    #[derive(Serialize)]
+    struct Args {}
+
+    let mut record = Record::new("test", &Args {});
+    record.lower_is_better("mean", f64::NAN);
+    record.higher_is_better("rate", f64::INFINITY);
+    record.higher_is_better("count", 3.0);
+    assert_eq!(record.stats.keys().collect::<Vec<_>>(), ["count"]);
+
+    // A line written before non-finite values were refused.
+    let good = serde_json::to_string(&record).unwrap();
+    let null = concat!(r#"{"benchmark":"test","params":{},"machine":"m","time":0,"#,
+                       r#""stats":{"mean":{"value":null,"better":"lower"}}}"#);
+    let text = format!("{}\n{}\n\n{}\n", good, null, good);
+    let (records, skipped) = parse_records(text.as_bytes()).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(skipped.len(), 1);
+    assert!(skipped[0].starts_with("line 2: "));
+}
@@ -0,0 +1,42 @@
+/// A small, fast, seedable pseudo-random number generator (SplitMix64).
+///
+/// This is not remotely cryptographic; it's here so that randomized
+/// benchmark configurations can be reproduced from a seed, without pulling in
+/// a dependency.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Return a number uniformly distributed in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        assert!(n > 0);
+        ((self.next_u64() as u128 * n as u128) >> 64) as usize
+    }
+
+    /// Return a number uniformly distributed in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+#[test]
+fn below_in_range() {
+    let mut rng = Rng::new(42);
+    let mut seen = [false; 7];
+    for _ in 0..1000 {
+        seen[rng.below(7)] = true;
+    }
+    assert!(seen.iter().all(|&s| s));
+}
@@ -0,0 +1,120 @@
+use crate::Stats;
+use std::io::{self, BufRead, Write};
+
+/// A collection of measurements, retained so that we can compute percentiles
+/// of their distribution, not just moments.
+#[derive(Clone, Debug, Default)]
+pub struct Samples {
+    values: Vec<f64>,
+    sorted: bool,
+}
+
+impl Samples {
+    pub fn new() -> Samples {
+        Default::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Samples {
+        Samples { values: Vec::with_capacity(capacity), sorted: false }
+    }
+
+    pub fn push(&mut self, x: f64) {
+        self.values.push(x);
+        self.sorted = false;
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn values(&self) -> &[f64] {
+        &self.values
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.values.iter().copied().collect()
+    }
+
+    /// Return the `q`'th quantile of the samples, where `q` is between 0 and 1,
+    /// using the nearest-rank method. Panics if there are no samples.
+    pub fn quantile(&mut self, q: f64) -> f64 {
+        assert!(!self.values.is_empty(), "quantile of empty sample set");
+        if !self.sorted {
+            self.values.sort_unstable_by(f64::total_cmp);
+            self.sorted = true;
+        }
+        let rank = (q * self.values.len() as f64).ceil() as usize;
+        self.values[rank.clamp(1, self.values.len()) - 1]
+    }
+
+    pub fn max(&mut self) -> f64 {
+        self.quantile(1.0)
+    }
+
+    /// Write the samples to `out`, one per line, in the samples' own units
+    /// (seconds, for times), in a form `Samples::read` accepts.
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for x in &self.values {
+            writeln!(out, "{}", x)?;
+        }
+        out.flush()
+    }
+
+    /// Read samples written by `Samples::write`. Blank lines and lines starting
+    /// with `#` are ignored.
+    pub fn read<R: BufRead>(input: R) -> io::Result<Samples> {
+        let mut samples = Samples::new();
+        for (number, line) in input.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let x = line.parse().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               format!("line {}: bad sample '{}': {}", number + 1, line, e))
+            })?;
+            samples.push(x);
+        }
+        Ok(samples)
+    }
+}
+
+impl Extend<f64> for Samples {
+    fn extend<T: IntoIterator<Item=f64>>(&mut self, iter: T) {
+        iter.into_iter().for_each(|x| self.push(x));
+    }
+}
+
+impl std::iter::FromIterator<f64> for Samples {
+    fn from_iter<T>(iter: T) -> Samples
+        where T: IntoIterator<Item = f64>
+    {
+        let mut s = Samples::new();
+        s.extend(iter);
+        s
+    }
+}
+
+#[test]
+fn quantiles() {
+    let mut samples: Samples = (1..=100).rev().map(|i| i as f64).collect();
+    assert_eq!(samples.quantile(0.0), 1.0);
+    assert_eq!(samples.quantile(0.5), 50.0);
+    assert_eq!(samples.quantile(0.99), 99.0);
+    assert_eq!(samples.quantile(0.999), 100.0);
+    assert_eq!(samples.max(), 100.0);
+}
+
+#[test]
+fn write_and_read() {
+    let samples: Samples = [1.5e-6, 2e-9, 0.25].iter().copied().collect();
+    let mut text = vec![];
+    samples.write(&mut text).unwrap();
+    let read = Samples::read(&text[..]).unwrap();
+    assert_eq!(read.values(), samples.values());
+}
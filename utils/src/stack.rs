@@ -0,0 +1,118 @@
+//! Measuring how much of its stack a thread actually uses.
+//!
+//! A thread calls `paint_stack` when it starts, which fills the unused part of
+//! its stack with a pattern. When it's done, `PaintedStack::high_water` finds
+//! the lowest address where the pattern has been overwritten, which is as deep
+//! as the stack ever got.
+//!
+//! Painting touches every page of the stack, so a painted thread's whole stack
+//! becomes resident: don't measure memory use while painting.
+
+use std::io;
+use std::ptr;
+
+/// The pattern we fill stacks with.
+const PAINT: u64 = 0x5eed_5eed_5eed_5eed;
+
+/// How far below its own frame `paint_stack` stops painting, to leave room for
+/// the functions it calls while painting.
+const MARGIN: usize = 4096;
+
+/// The bounds of a thread's stack, which `paint_stack` has painted.
+pub struct PaintedStack {
+    low: usize,
+    high: usize,
+}
+
+/// Paint the calling thread's stack below the current frame, and return a
+/// `PaintedStack` that can later measure how much of it the thread used.
+///
+/// The high-water mark can't come out lower than the stack in use when the
+/// thread called this function, plus `MARGIN`.
+#[inline(never)]
+pub fn paint_stack() -> io::Result<PaintedStack> {
+    let (low, high) = stack_bounds()?;
+    let marker = 0_u8;
+    let here = std::hint::black_box(&marker) as *const u8 as usize;
+    let top = here - MARGIN;
+    let mut word = align_up(low);
+    while word + 8 <= top {
+        unsafe { ptr::write_volatile(word as *mut u64, PAINT) };
+        word += 8;
+    }
+    Ok(PaintedStack { low, high })
+}
+
+impl PaintedStack {
+    /// Return the greatest number of bytes of stack the thread has used since
+    /// it was painted. This must be called on the thread that painted it.
+    pub fn high_water(&self) -> usize {
+        let mut word = align_up(self.low);
+        while word < self.high && unsafe { ptr::read_volatile(word as *const u64) } == PAINT {
+            word += 8;
+        }
+        self.high - word
+    }
+}
+
+fn align_up(address: usize) -> usize {
+    (address + 7) & !7
+}
+
+/// Return the lowest and highest addresses of the calling thread's stack,
+/// excluding its guard page.
+fn stack_bounds() -> io::Result<(usize, usize)> {
+    unsafe {
+        let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+        let error = libc::pthread_getattr_np(libc::pthread_self(), &mut attr);
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error));
+        }
+        let mut addr = ptr::null_mut();
+        let mut size = 0;
+        let mut guard = 0;
+        libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+        libc::pthread_attr_getguardsize(&attr, &mut guard);
+        libc::pthread_attr_destroy(&mut attr);
+
+        // Some versions of glibc include the guard page in the reported
+        // stack, and some don't. Skip it either way.
+        Ok((addr as usize + guard, addr as usize + size))
+    }
+}
+
+/// Summarize the high-water marks of a set of threads' stacks.
+pub fn describe_high_water(marks: &[usize]) -> String {
+    if marks.is_empty() {
+        return "no stack high-water marks".to_string();
+    }
+    let kib = |bytes: usize| bytes as f64 / 1024.0;
+    format!("stack high-water mark over {} threads: max {:.1}KiB, mean {:.1}KiB, min {:.1}KiB",
+            marks.len(),
+            kib(*marks.iter().max().unwrap()),
+            kib(marks.iter().sum::<usize>()) / marks.len() as f64,
+            kib(*marks.iter().min().unwrap()))
+}
+
+#[test]
+fn high_water() {
+    #[inline(never)]
+    fn use_stack(bytes: usize) -> u8 {
+        let mut buf = [0_u8; 64 * 1024];
+        std::hint::black_box(&mut buf[..bytes])[bytes - 1]
+    }
+
+    let mark = std::thread::Builder::new()
+        .stack_size(512 * 1024)
+        .spawn(|| {
+            let stack = paint_stack().unwrap();
+            let before = stack.high_water();
+            use_stack(64 * 1024);
+            (before, stack.high_water())
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    assert!(mark.0 < 64 * 1024);
+    assert!(mark.1 >= 64 * 1024 && mark.1 < 512 * 1024);
+}
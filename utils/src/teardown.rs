@@ -0,0 +1,172 @@
+//! Measuring how long it takes to shut a benchmark's tasks down, and how much
+//! memory the process gets back afterwards.
+//!
+//! Most of what we measure is a steady state, but long-running services create
+//! and destroy tasks all the time, so what it costs to get rid of them matters
+//! too. A brigade calls `tear_down` or `tear_down_async` to close the head of
+//! its chain, wait for every task to see end-of-file and exit, and report how
+//! that went.
+
+use crate::UsefulDuration;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::time::Instant;
+
+/// Tear down `tasks` tasks: call `close` to close the head of their chain, and
+/// `join` to wait for them all to exit. Unless `quiet`, print how long that
+/// took and how much memory it returned. Return whatever `join` returns.
+///
+/// For a chain of sockets, `close` should shut the first socket down for
+/// writing, rather than just dropping a handle on it: that ends a ring too,
+/// since the last task's writes fail.
+pub fn tear_down<T>(tasks: usize, quiet: bool,
+                    close: impl FnOnce() -> io::Result<()>,
+                    join: impl FnOnce() -> T)
+                    -> io::Result<T>
+{
+    let teardown = Teardown::start(tasks);
+    close()?;
+    let joined = join();
+    teardown.report(quiet);
+    Ok(joined)
+}
+
+/// Like `tear_down`, but await `join` instead of calling it.
+pub async fn tear_down_async<T>(tasks: usize, quiet: bool,
+                                close: impl FnOnce() -> io::Result<()>,
+                                join: impl Future<Output = T>)
+                                -> io::Result<T>
+{
+    let teardown = Teardown::start(tasks);
+    close()?;
+    let joined = join.await;
+    teardown.report(quiet);
+    Ok(joined)
+}
+
+/// A teardown in progress.
+pub struct Teardown {
+    tasks: usize,
+    start: Instant,
+    rss_before: Option<u64>,
+}
+
+/// How a teardown went.
+#[derive(Clone, Copy, Debug)]
+pub struct TornDown {
+    /// The number of tasks shut down.
+    pub tasks: usize,
+
+    /// How long it took for them all to exit, in seconds.
+    pub elapsed: f64,
+
+    /// The process's resident set size before and after, in bytes, if we
+    /// could determine it.
+    pub rss_before: Option<u64>,
+    pub rss_after: Option<u64>,
+}
+
+impl Teardown {
+    /// Note the resident set size, and start timing the teardown of `tasks`
+    /// tasks.
+    pub fn start(tasks: usize) -> Teardown {
+        let rss_before = resident_set_size();
+        Teardown { tasks, start: Instant::now(), rss_before }
+    }
+
+    /// Stop timing, now that every task has exited, and note the resident set
+    /// size again.
+    pub fn finish(self) -> TornDown {
+        let elapsed = (Instant::now() - self.start).as_secs_f64();
+        TornDown {
+            tasks: self.tasks,
+            elapsed,
+            rss_before: self.rss_before,
+            rss_after: resident_set_size(),
+        }
+    }
+
+    fn report(self, quiet: bool) {
+        let torn_down = self.finish();
+        if !quiet {
+            eprintln!("{}", torn_down);
+        }
+    }
+}
+
+impl TornDown {
+    /// Return the number of bytes of resident memory the teardown returned to
+    /// the operating system. This is negative if the process grew instead.
+    pub fn returned(&self) -> Option<i64> {
+        Some(self.rss_before? as i64 - self.rss_after? as i64)
+    }
+}
+
+impl fmt::Display for TornDown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tasks = self.tasks.max(1) as f64;
+        write!(f, "teardown of {} tasks: {} ({} per task)",
+               self.tasks,
+               UsefulDuration::from(self.elapsed),
+               UsefulDuration::from(self.elapsed / tasks))?;
+        match (self.rss_before, self.rss_after, self.returned()) {
+            (Some(before), Some(after), Some(returned)) if returned > 0 => {
+                write!(f, ", RSS {} -> {}, {} returned to the OS ({} per task)",
+                       Size(before as f64), Size(after as f64), Size(returned as f64),
+                       Size(returned as f64 / tasks))
+            }
+            (Some(before), Some(after), _) => {
+                write!(f, ", RSS {} -> {}, none returned to the OS",
+                       Size(before as f64), Size(after as f64))
+            }
+            _ => write!(f, ", RSS unknown"),
+        }
+    }
+}
+
+/// A number of bytes, displayed in sensible units.
+struct Size(f64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const K: f64 = 1024.0;
+        if self.0 < K {
+            write!(f, "{:.0}B", self.0)
+        } else if self.0 < K * K {
+            write!(f, "{:.1}KiB", self.0 / K)
+        } else {
+            write!(f, "{:.1}MiB", self.0 / (K * K))
+        }
+    }
+}
+
+/// Return this process's resident set size, in bytes, or `None` if it can't
+/// be determined.
+pub fn resident_set_size() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size as u64)
+}
+
+#[test]
+fn returned() {
+    // Map and unmap memory directly, since allocators may hold on to freed
+    // blocks.
+    let len = 64 << 20;
+    let block = unsafe {
+        libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                   libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+    };
+    assert_ne!(block, libc::MAP_FAILED);
+    unsafe { std::ptr::write_bytes(block as *mut u8, 1, len) };
+
+    let teardown = Teardown::start(1);
+    unsafe { libc::munmap(block, len) };
+    let torn_down = teardown.finish();
+    assert!(torn_down.elapsed >= 0.0);
+    if let Some(returned) = torn_down.returned() {
+        assert!(returned >= 32 << 20, "returned only {} bytes", returned);
+    }
+}
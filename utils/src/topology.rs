@@ -0,0 +1,275 @@
+use crate::Rng;
+use std::io;
+
+/// The most nodes `Topology::tree` will build. Each node needs its own thread
+/// or task and a pair of file descriptors per link, so no tree this large
+/// could run anyway.
+pub const MAX_TREE_NODES: usize = 1 << 20;
+
+/// A directed acyclic graph of tasks connected by links.
+///
+/// Each node is a task that, once per iteration, reads one message from each
+/// of its upstream links and then writes one message to each of its downstream
+/// links. Links are identified by index, and each has exactly one reader and
+/// one writer. The benchmark driver writes to the `input` link and reads from
+/// the `output` link; every other link runs between two nodes.
+///
+/// Nodes are always listed in topological order: a node's upstream links are
+/// all written by nodes earlier in the list.
+#[derive(Debug)]
+pub struct Topology {
+    pub nodes: Vec<Node>,
+    pub num_links: usize,
+    pub input: usize,
+    pub output: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Node {
+    pub upstream: Vec<usize>,
+    pub downstream: Vec<usize>,
+}
+
+/// The ends of a topology's links, as handed out by `Topology::links`.
+pub struct Links<R, W> {
+    /// The writing end of the input link, for the driver.
+    pub input: W,
+
+    /// The reading end of the output link, for the driver.
+    pub output: R,
+
+    /// For each node, the reading ends of its upstream links and the writing
+    /// ends of its downstream links.
+    pub nodes: Vec<(Vec<R>, Vec<W>)>,
+}
+
+impl Topology {
+    fn new(num_nodes: usize) -> Topology {
+        let mut topology = Topology {
+            nodes: (0..num_nodes).map(|_| Node::default()).collect(),
+            num_links: 0,
+            input: 0,
+            output: 0,
+        };
+        topology.input = topology.new_link();
+        topology.output = topology.new_link();
+        topology
+    }
+
+    fn new_link(&mut self) -> usize {
+        self.num_links += 1;
+        self.num_links - 1
+    }
+
+    /// Add a link from node `from` to node `to`.
+    fn connect(&mut self, from: usize, to: usize) {
+        let link = self.new_link();
+        self.nodes[from].downstream.push(link);
+        self.nodes[to].upstream.push(link);
+    }
+
+    /// A straight chain of `len` nodes: the bucket brigade.
+    pub fn chain(len: usize) -> Topology {
+        assert!(len >= 1);
+        let mut topology = Topology::new(len);
+        topology.nodes[0].upstream.push(topology.input);
+        for node in 1..len {
+            topology.connect(node - 1, node);
+        }
+        topology.nodes[len - 1].downstream.push(topology.output);
+        topology
+    }
+
+    /// One node that scatters to `width` workers, and one node that gathers
+    /// their results.
+    pub fn fan(width: usize) -> Topology {
+        assert!(width >= 1);
+        let gather = width + 1;
+        let mut topology = Topology::new(width + 2);
+        topology.nodes[0].upstream.push(topology.input);
+        for worker in 1..=width {
+            topology.connect(0, worker);
+            topology.connect(worker, gather);
+        }
+        topology.nodes[gather].downstream.push(topology.output);
+        topology
+    }
+
+    /// A complete `arity`-ary tree with `depth` levels, through which a request
+    /// flows down from the root to the leaves, followed by a mirror-image tree
+    /// through which the leaves' replies are aggregated back up to the root.
+    ///
+    /// The leaves are shared by both trees: each leaf receives a request from
+    /// its parent, and sends its reply to its parent's counterpart in the
+    /// upward tree.
+    ///
+    /// Return an error if the tree would have more than `MAX_TREE_NODES`
+    /// nodes, rather than trying to allocate them.
+    pub fn tree(arity: usize, depth: usize) -> Result<Topology, String> {
+        assert!(arity >= 1 && depth >= 1);
+
+        // Number the downward tree's nodes level by level, so the children of
+        // node `i` are `arity * i + 1 ..= arity * i + arity`.
+        let too_big = || {
+            format!("a {}-ary tree {} levels deep has more than {} nodes",
+                    arity, depth, MAX_TREE_NODES)
+        };
+        let mut down: usize = 0;
+        let mut level_width: usize = 1;
+        for _level in 0..depth {
+            down = down.checked_add(level_width).ok_or_else(too_big)?;
+            level_width = level_width.checked_mul(arity).ok_or_else(too_big)?;
+        }
+        let internal = down - level_width / arity;
+        if down.checked_add(internal).is_none_or(|total| total > MAX_TREE_NODES) {
+            return Err(too_big());
+        }
+
+        // The upward tree's counterpart of internal node `i` is node
+        // `down + internal - 1 - i`, so the upward tree's root comes last.
+        let up = |i: usize| down + internal - 1 - i;
+
+        let mut topology = Topology::new(down + internal);
+        topology.nodes[0].upstream.push(topology.input);
+        for parent in 0..internal {
+            for child in arity * parent + 1..=arity * parent + arity {
+                topology.connect(parent, child);
+            }
+        }
+        for parent in (0..internal).rev() {
+            for child in arity * parent + 1..=arity * parent + arity {
+                let from = if child < internal { up(child) } else { child };
+                topology.connect(from, up(parent));
+            }
+        }
+        let root = if internal == 0 { 0 } else { up(0) };
+        topology.nodes[root].downstream.push(topology.output);
+        Ok(topology)
+    }
+
+    /// A random DAG of `num_nodes` nodes, generated from `seed`.
+    ///
+    /// The first node reads the input, and the last writes the output. Every
+    /// other node reads from between one and `max_fan_in` randomly chosen
+    /// earlier nodes. Any node left with no downstream links feeds the last
+    /// node.
+    pub fn random_dag(num_nodes: usize, max_fan_in: usize, seed: u64) -> Topology {
+        assert!(num_nodes >= 1 && max_fan_in >= 1);
+        let mut rng = Rng::new(seed);
+        let last = num_nodes - 1;
+        let mut topology = Topology::new(num_nodes);
+        topology.nodes[0].upstream.push(topology.input);
+        for node in 1..last {
+            let fan_in = 1 + rng.below(max_fan_in.min(node));
+            let mut sources: Vec<usize> = vec![];
+            while sources.len() < fan_in {
+                let source = rng.below(node);
+                if !sources.contains(&source) {
+                    sources.push(source);
+                }
+            }
+            for source in sources {
+                topology.connect(source, node);
+            }
+        }
+        for node in 0..last {
+            if topology.nodes[node].downstream.is_empty() {
+                topology.connect(node, last);
+            }
+        }
+        topology.nodes[last].downstream.push(topology.output);
+        topology
+    }
+
+    /// Return the number of links on the longest path from the input to the
+    /// output, counting both.
+    pub fn critical_path(&self) -> usize {
+        let mut depth = vec![0; self.num_links];
+        depth[self.input] = 1;
+        for node in &self.nodes {
+            let deepest = node.upstream.iter().map(|&link| depth[link]).max().unwrap();
+            for &link in &node.downstream {
+                depth[link] = deepest + 1;
+            }
+        }
+        depth[self.output]
+    }
+
+    /// Create a pipe for every link by calling `pair`, which returns its
+    /// reading and writing ends, and hand the ends out to the driver and the
+    /// nodes.
+    pub fn links<R, W>(&self, mut pair: impl FnMut() -> io::Result<(R, W)>)
+                       -> io::Result<Links<R, W>>
+    {
+        let mut reads = Vec::with_capacity(self.num_links);
+        let mut writes = Vec::with_capacity(self.num_links);
+        for _link in 0..self.num_links {
+            let (read, write) = pair()?;
+            reads.push(Some(read));
+            writes.push(Some(write));
+        }
+        let input = writes[self.input].take().unwrap();
+        let output = reads[self.output].take().unwrap();
+        let nodes = self.nodes.iter()
+            .map(|node| {
+                let upstream = node.upstream.iter()
+                    .map(|&l| reads[l].take().unwrap())
+                    .collect();
+                let downstream = node.downstream.iter()
+                    .map(|&l| writes[l].take().unwrap())
+                    .collect();
+                (upstream, downstream)
+            })
+            .collect();
+        Ok(Links { input, output, nodes })
+    }
+}
+
+#[test]
+fn shapes() {
+    let chain = Topology::chain(500);
+    assert_eq!(chain.nodes.len(), 500);
+    assert_eq!(chain.num_links, 501);
+    assert_eq!(chain.critical_path(), 501);
+
+    let fan = Topology::fan(10);
+    assert_eq!(fan.nodes.len(), 12);
+    assert_eq!(fan.num_links, 22);
+    assert_eq!(fan.critical_path(), 4);
+
+    // 7 nodes down, 3 up.
+    let tree = Topology::tree(2, 3).unwrap();
+    assert_eq!(tree.nodes.len(), 10);
+    assert_eq!(tree.num_links, 14);
+    assert_eq!(tree.critical_path(), 6);
+    assert_eq!(Topology::tree(3, 1).unwrap().critical_path(), 2);
+    assert!(Topology::tree(1000, 100).is_err());
+    assert!(Topology::tree(10, 11).is_err());
+
+    for seed in 0..10 {
+        let dag = Topology::random_dag(50, 3, seed);
+        assert!(dag.critical_path() >= 3);
+        for (i, node) in dag.nodes.iter().enumerate() {
+            assert!(!node.upstream.is_empty(), "node {} has no upstream", i);
+            assert!(!node.downstream.is_empty(), "node {} has no downstream", i);
+        }
+    }
+}
+
+#[test]
+fn links() {
+    let fan = Topology::fan(3);
+    let mut next = 0;
+    let links = fan.links(|| {
+        next += 1;
+        Ok((next, next))
+    }).unwrap();
+
+    // Each link's ends went to its reader and writer.
+    assert_eq!(links.input, links.nodes[0].0[0]);
+    assert_eq!(links.output, links.nodes[4].1[0]);
+    for (upstream, _) in &links.nodes[1..4] {
+        assert!(links.nodes[0].1.contains(&upstream[0]));
+    }
+    assert_eq!(links.nodes[4].0.len(), 3);
+}
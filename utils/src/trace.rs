@@ -0,0 +1,235 @@
+use crate::{Samples, Stats, UsefulDuration};
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::{Arc, Mutex};
+
+/// Return the current `CLOCK_MONOTONIC` time, in nanoseconds.
+///
+/// This is the same clock `Instant` uses, but as a plain number it's cheap to
+/// store and compare across threads.
+pub fn now_ns() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Return the CPU the calling thread is running on.
+pub fn current_cpu() -> u32 {
+    unsafe { libc::sched_getcpu() as u32 }
+}
+
+/// One task's record of a single message passing through it. Times are from
+/// `now_ns`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HopEvent {
+    /// When the task's read returned the message.
+    pub received: u64,
+
+    /// When the task began writing the message downstream.
+    pub sending: u64,
+
+    /// When the task's write returned.
+    pub sent: u64,
+
+    /// The CPU the task was running on when it received the message.
+    pub cpu: u32,
+}
+
+/// A fixed-size ring buffer of a task's most recent `HopEvent`s.
+///
+/// All the space is allocated up front, so recording an event never
+/// allocates.
+#[derive(Debug)]
+pub struct TraceRing {
+    events: Vec<HopEvent>,
+
+    /// The total number of events ever recorded.
+    count: usize,
+}
+
+/// A `TraceRing` that a task records into and the driver later examines.
+pub type SharedTrace = Arc<Mutex<TraceRing>>;
+
+impl TraceRing {
+    pub fn with_capacity(capacity: usize) -> TraceRing {
+        assert!(capacity > 0);
+        TraceRing { events: vec![HopEvent::default(); capacity], count: 0 }
+    }
+
+    pub fn shared(capacity: usize) -> SharedTrace {
+        Arc::new(Mutex::new(TraceRing::with_capacity(capacity)))
+    }
+
+    pub fn record(&mut self, event: HopEvent) {
+        let len = self.events.len();
+        self.events[self.count % len] = event;
+        self.count += 1;
+    }
+
+    /// The total number of events ever recorded.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The number of events still held in the ring.
+    pub fn retained(&self) -> usize {
+        self.count.min(self.events.len())
+    }
+
+    /// Return the `n`'th event ever recorded, if it's still in the ring.
+    pub fn get(&self, n: usize) -> Option<&HopEvent> {
+        if n < self.count && self.count - n <= self.events.len() {
+            Some(&self.events[n % self.events.len()])
+        } else {
+            None
+        }
+    }
+}
+
+/// An analysis of traces taken from a chain of tasks in which exactly one
+/// message is in flight at a time, so that each task's `n`'th event belongs to
+/// the same iteration as the driver's `n`'th event.
+///
+/// The wake latency of a hop is the time from the upstream task beginning its
+/// write to the downstream task's read returning. Hop 0 is the driver waking
+/// the first task; the last hop is the last task waking the driver.
+pub struct TraceReport {
+    iterations: usize,
+    wake: Samples,
+    same_cpu: Stats,
+    cross_cpu: Stats,
+    run: Stats,
+    hops: Vec<HopSummary>,
+}
+
+struct HopSummary {
+    index: usize,
+    wake: Stats,
+    max: f64,
+    crossings: usize,
+}
+
+/// Return the range of iterations, numbered from the first ever recorded, that
+/// are held in full in `driver` and all of `tasks`.
+pub fn complete_iterations<T>(driver: &TraceRing, tasks: &[T]) -> Range<usize>
+    where T: Deref<Target = TraceRing>
+{
+    // A task records a message only after passing it along, so by the time
+    // the driver has the final iteration's message back, the tasks may not
+    // all have recorded it yet. Every earlier iteration is complete.
+    let end = driver.count().saturating_sub(1);
+    assert!(tasks.iter().all(|t| t.count() >= end),
+            "tasks saw fewer messages than the driver");
+    let start = tasks.iter()
+        .map(|t| t.count() - t.retained())
+        .fold(driver.count() - driver.retained(), usize::max)
+        .min(end);
+    start..end
+}
+
+impl TraceReport {
+    /// Analyze the iterations still held in all of `driver` and `tasks`. In
+    /// each of the driver's events, `received` is when the message came back
+    /// out of the chain.
+    pub fn new<T>(driver: &TraceRing, tasks: &[T]) -> TraceReport
+        where T: Deref<Target = TraceRing>
+    {
+        let iterations = complete_iterations(driver, tasks);
+        let (start, end) = (iterations.start, iterations.end);
+        let iterations = end - start;
+
+        let mut report = TraceReport {
+            iterations,
+            wake: Samples::with_capacity(iterations * (tasks.len() + 1)),
+            same_cpu: Stats::new(),
+            cross_cpu: Stats::new(),
+            run: Stats::new(),
+            hops: (0..=tasks.len())
+                .map(|index| HopSummary { index, wake: Stats::new(), max: 0.0, crossings: 0 })
+                .collect(),
+        };
+
+        for n in start..end {
+            let start = driver.get(n).unwrap();
+            let mut upstream = start;
+            let events = tasks.iter().map(|t| t.get(n).unwrap()).chain(Some(start));
+            for (hop, event) in report.hops.iter_mut().zip(events) {
+                let wake = event.received.saturating_sub(upstream.sending) as f64 * 1e-9;
+                report.wake.push(wake);
+                hop.wake.push(wake);
+                hop.max = hop.max.max(wake);
+                if event.cpu == upstream.cpu {
+                    report.same_cpu.push(wake);
+                } else {
+                    report.cross_cpu.push(wake);
+                    hop.crossings += 1;
+                }
+                upstream = event;
+            }
+            for event in tasks.iter().map(|t| t.get(n).unwrap()) {
+                report.run.push(event.sending.saturating_sub(event.received) as f64 * 1e-9);
+            }
+        }
+
+        report
+    }
+}
+
+impl fmt::Display for TraceReport {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.iterations == 0 {
+            return writeln!(fmt, "no iterations traced");
+        }
+
+        let mut wake = self.wake.clone();
+        writeln!(fmt, "traced {} iterations of {} hops each", self.iterations, self.hops.len())?;
+        writeln!(fmt, "wake latency: p50 {}, p90 {}, p99 {}, max {}",
+                 UsefulDuration::from(wake.quantile(0.5)),
+                 UsefulDuration::from(wake.quantile(0.9)),
+                 UsefulDuration::from(wake.quantile(0.99)),
+                 UsefulDuration::from(wake.max()))?;
+        for (label, stats) in [("same CPU", &self.same_cpu), ("cross-CPU", &self.cross_cpu)] {
+            if stats.mean().is_finite() {
+                writeln!(fmt, "  {}: mean {}, stddev {}",
+                         label,
+                         UsefulDuration::from(stats.mean()),
+                         UsefulDuration::from(stats.population_stddev()))?;
+            }
+        }
+        writeln!(fmt, "time from receive to send: mean {}", UsefulDuration::from(self.run.mean()))?;
+
+        let mut slowest: Vec<&HopSummary> = self.hops.iter().collect();
+        slowest.sort_by(|a, b| b.wake.mean().total_cmp(&a.wake.mean()));
+        writeln!(fmt, "slowest hops by mean wake latency:")?;
+        for hop in slowest.iter().take(5) {
+            let name = if hop.index + 1 == self.hops.len() {
+                "driver".to_string()
+            } else {
+                format!("hop {}", hop.index)
+            };
+            writeln!(fmt, "  {}: mean {}, max {}, crossed CPUs in {:.0}% of iterations",
+                     name,
+                     UsefulDuration::from(hop.wake.mean()),
+                     UsefulDuration::from(hop.max),
+                     100.0 * hop.crossings as f64 / self.iterations as f64)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn trace_ring() {
+    let mut ring = TraceRing::with_capacity(3);
+    for i in 0..5 {
+        ring.record(HopEvent { received: i, ..HopEvent::default() });
+    }
+    assert_eq!(ring.count(), 5);
+    assert_eq!(ring.retained(), 3);
+    assert!(ring.get(1).is_none());
+    assert_eq!(ring.get(2).unwrap().received, 2);
+    assert_eq!(ring.get(4).unwrap().received, 4);
+    assert!(ring.get(5).is_none());
+}
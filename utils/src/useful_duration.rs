@@ -1,4 +1,7 @@
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
 use std::fmt;
+use std::str::FromStr;
 use std::time::Duration;
 
 #[derive(Copy, Clone, Debug)]
@@ -16,6 +19,12 @@ impl From<f64> for UsefulDuration {
     }
 }
 
+impl From<UsefulDuration> for Duration {
+    fn from(d: UsefulDuration) -> Self {
+        d.0
+    }
+}
+
 impl From<UsefulDuration> for f64 {
     fn from(d: UsefulDuration) -> Self {
         d.0.as_secs_f64()
@@ -38,3 +47,77 @@ impl fmt::Display for UsefulDuration {
         }
     }
 }
+
+/// Parse a duration written as a number followed by a unit: `ns`, `µs` (or
+/// `us`), `ms`, or `s`. This accepts everything `Display` produces.
+impl FromStr for UsefulDuration {
+    type Err = ParseDurationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let scale = match unit.trim() {
+            "ns" => 1e-9,
+            "µs" | "us" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "" => return Err(ParseDurationError(format!("duration '{}' needs a unit: ns, µs, us, ms or s", s))),
+            _ => return Err(ParseDurationError(format!("duration '{}' has an unrecognized unit", s))),
+        };
+        let number: f64 = number.parse()
+            .map_err(|_| ParseDurationError(format!("duration '{}' doesn't start with a number", s)))?;
+        if number.is_nan() || number < 0.0 {
+            return Err(ParseDurationError(format!("duration '{}' must be a non-negative number", s)));
+        }
+        Duration::try_from_secs_f64(number * scale)
+            .map(UsefulDuration)
+            .map_err(|e| ParseDurationError(format!("duration '{}' is out of range: {}", s, e)))
+    }
+}
+
+impl<'de> Deserialize<'de> for UsefulDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for UsefulDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseDurationError(String);
+
+impl fmt::Display for ParseDurationError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParseDurationError {}
+
+#[test]
+fn parse() {
+    let parse = |s: &str| s.parse::<UsefulDuration>().map(Duration::from);
+    assert_eq!(parse("30s").unwrap(), Duration::from_secs(30));
+    assert_eq!(parse("2.5ms").unwrap(), Duration::from_micros(2500));
+    assert_eq!(parse("100us").unwrap(), Duration::from_micros(100));
+    assert_eq!(parse("100µs").unwrap(), Duration::from_micros(100));
+    assert_eq!(parse("750ns").unwrap(), Duration::from_nanos(750));
+    assert!(parse("30").is_err());
+    assert!(parse("30m").is_err());
+    assert!(parse("s").is_err());
+    assert!(parse("-5ms").is_err());
+    assert!(parse("NaNs").is_err());
+    assert!(parse("99999999999999999999999s").is_err());
+
+    // Parsing is the inverse of `Display`.
+    for d in [Duration::ZERO, Duration::from_nanos(123), Duration::from_micros(45),
+              Duration::from_millis(67), Duration::from_secs(89)] {
+        assert_eq!(parse(&UsefulDuration::from(d).to_string()).unwrap(), d);
+    }
+}
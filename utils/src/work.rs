@@ -0,0 +1,71 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Simulated work for a task to do each time it handles a message: some CPU
+/// time, and a working set of memory to touch.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Work {
+    /// Nanoseconds to spin on the CPU.
+    pub spin_ns: u64,
+
+    /// Number of bytes of memory to write.
+    pub working_set: usize,
+}
+
+impl Work {
+    pub fn is_empty(&self) -> bool {
+        self.spin_ns == 0 && self.working_set == 0
+    }
+
+    /// Do the work, with the working set on the calling thread's stack, as a
+    /// thread's request handler's local variables would be.
+    pub fn perform_on_stack(&self) {
+        spin(self.spin_ns);
+        if self.working_set > 0 {
+            touch_stack(self.working_set);
+        }
+    }
+
+    /// Do the work, with the working set in `buf`, as an async task's state
+    /// would be. `buf` should be `self.working_set` bytes long.
+    pub fn perform_in(&self, buf: &mut [u8]) {
+        spin(self.spin_ns);
+        touch(buf);
+    }
+}
+
+/// Busy-wait for `ns` nanoseconds.
+pub fn spin(ns: u64) {
+    if ns == 0 {
+        return;
+    }
+    let start = Instant::now();
+    let duration = Duration::from_nanos(ns);
+    while start.elapsed() < duration {
+        std::hint::spin_loop();
+    }
+}
+
+/// Write every byte of `buf`.
+pub fn touch(buf: &mut [u8]) {
+    buf.fill(black_box(0x55));
+    black_box(buf);
+}
+
+/// Write `bytes` bytes of the calling thread's stack.
+///
+/// Rust has no variable-length stack arrays, so this recurses, touching a
+/// fixed-size chunk in each frame.
+#[inline(never)]
+pub fn touch_stack(bytes: usize) {
+    const CHUNK: usize = 4096;
+    let mut chunk = [0_u8; CHUNK];
+    touch(&mut chunk[..bytes.min(CHUNK)]);
+    if bytes > CHUNK {
+        touch_stack(bytes - CHUNK);
+    }
+
+    // Keep `chunk` live across the recursive call, so that the call can't be
+    // turned into a loop that reuses this frame.
+    black_box(&chunk);
+}